@@ -0,0 +1,57 @@
+use std::fmt;
+
+use ffmpeg_next::{util::error::EAGAIN, Error as AvError};
+
+#[derive(Debug)]
+pub enum Error {
+    // avformat_open_input / avio_open
+    Open(String, AvError),
+    // stream discovery
+    Probe(String),
+    // decoder / encoder lookup and setup
+    Codec(&'static str, AvError),
+    // filter graph creation and parsing
+    Filter(&'static str, AvError),
+    // header / packet / trailer writing
+    Mux(&'static str, AvError),
+    // request settings no encoder or format can honour, e.g. an unknown codec name
+    Config(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// EAGAIN / EOF from the send / receive calls mean "needs more input" or
+// "fully drained", not a failure
+pub fn is_drained(err: &AvError) -> bool {
+    matches!(err, AvError::Eof | AvError::Other { errno: EAGAIN })
+}
+
+// a codec that takes no more input until its output is received
+pub fn is_full(err: &AvError) -> bool {
+    matches!(err, AvError::Other { errno: EAGAIN })
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Open(url, err) => write!(f, "failed to open {}: {}", url, err),
+            Error::Probe(msg) => write!(f, "failed to probe input: {}", msg),
+            Error::Codec(msg, err) => write!(f, "codec error, {}: {}", msg, err),
+            Error::Filter(msg, err) => write!(f, "filter error, {}: {}", msg, err),
+            Error::Mux(msg, err) => write!(f, "mux error, {}: {}", msg, err),
+            Error::Config(msg) => write!(f, "invalid settings: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Probe(_) | Error::Config(_) => None,
+            Error::Open(_, err)
+            | Error::Codec(_, err)
+            | Error::Filter(_, err)
+            | Error::Mux(_, err) => Some(err),
+        }
+    }
+}
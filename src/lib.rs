@@ -1,180 +1,573 @@
+pub mod error;
 pub mod serve;
 pub mod trans;
 
-use crossbeam_channel::Receiver;
-use ffmpeg_next::{dictionary::Owned, Packet};
-use serve::route::ThreadMsg;
-use std::{env, path::Path};
-use trans::{ffmpeg::StreamCtx, filter::FilterCtx, sync::TimeGap};
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
+use error::{is_drained, is_full, Error, Result};
+use ffmpeg_next::{
+    decoder, encoder,
+    format::{context::Input, stream::Stream},
+    frame::Video,
+    media::Type,
+    Packet, Rational,
+};
+use serve::route::{OSDReq, ThreadMsg};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+use trans::{
+    audio::AudioCtx,
+    ffmpeg::{FmtCtx, StreamCtx, StreamInfo},
+    filter::FilterCtx,
+    interrupt::Interrupt,
+    output::{start_all, video_clock, write_all},
+    source::{EofAction, Pacer},
+    stats::Stats,
+    sync::{Rebase, SyncCtx},
+};
 
-pub fn ffmtrans_with_filter(osd: &str, rx: Receiver<ThreadMsg>) {
-    // parse command line arguments
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 3 {
-        panic!("Usage: ./rtsp_to_rtmp <rtsp://stream-url> <rtmp://stream-url>");
+pub fn ffmtrans_with_filter(
+    req: &OSDReq,
+    rx: Receiver<ThreadMsg>,
+    ready: Sender<StreamInfo>,
+    stats: &Stats,
+    interrupt: &Interrupt,
+) -> Result<()> {
+    // init stream context
+    let mut stream_ctx = StreamCtx::init(
+        Path::new(&req.input),
+        req.in_dict(),
+        &req.outputs(),
+        &req.encoder,
+        true,
+        interrupt,
+    )?;
+    let info = stream_ctx.info();
+    let mut enc_ctx = stream_ctx.enc_ctx.take().ok_or(Error::Codec(
+        "video encoder not opened",
+        ffmpeg_next::Error::EncoderNotFound,
+    ))?;
+
+    // filter init, a bad OSD fails before any output is opened
+    let mut filter_ctx =
+        FilterCtx::init_filter(&stream_ctx.dec_ctx, &req.osd.to_filter(), &req.encoder)?;
+
+    // open the outputs and write their headers
+    start_all(&mut stream_ctx.fmt_ctx.outputs, stats)?;
+
+    // pipeline is ready, the handler may answer now
+    stats.running(info.clone());
+    let _ = ready.send(info);
+
+    // a failing loop still finalises the outputs before its error is returned
+    let mut reader = Reader::new(req, rx, stats, interrupt);
+    let result = filter_loop(&mut reader, &mut stream_ctx, &mut enc_ctx, &mut filter_ctx);
+
+    // send EOF through the pipeline and finalise the output container
+    let StreamCtx {
+        dec_ctx,
+        de_frame,
+        audio,
+        fmt_ctx,
+        ..
+    } = &mut stream_ctx;
+    let flushed = flush_video(
+        dec_ctx,
+        de_frame,
+        &mut enc_ctx,
+        &mut filter_ctx,
+        fmt_ctx,
+        stats,
+    );
+    if let Err(e) = flushed {
+        log::warn!("flush on shutdown failed: {}", e);
     }
-    let input_path = Path::new(&args[1]);
-    let output_url = Path::new(&args[2]);
+    if let Err(e) = finish_output(audio, fmt_ctx, stats) {
+        log::warn!("finishing the outputs failed: {}", e);
+    }
+    result?;
+    match reader.finished {
+        true => stats.finished(),
+        false => stats.stopped(),
+    }
+    Ok(())
+}
 
+pub fn ffmtrans_remux(
+    req: &OSDReq,
+    rx: Receiver<ThreadMsg>,
+    ready: Sender<StreamInfo>,
+    stats: &Stats,
+    interrupt: &Interrupt,
+) -> Result<()> {
     // init stream context
-    let mut options = Owned::new();
-    options.set("rtsp_transport", "tcp");
-    options.set("max_delay", "500");
-    let mut stream_ctx = StreamCtx::init(input_path, Some(options), output_url, "flv", None);
-    let mut fmt_ctx = stream_ctx.fmt_ctx;
+    let mut stream_ctx = StreamCtx::init(
+        Path::new(&req.input),
+        req.in_dict(),
+        &req.outputs(),
+        &req.encoder,
+        false,
+        interrupt,
+    )?;
+    let info = stream_ctx.info();
 
-    // write header
-    fmt_ctx
-        .out_fmt_ctx
-        .write_header()
-        .expect("Failed to write header");
+    // open the outputs and write their headers
+    start_all(&mut stream_ctx.fmt_ctx.outputs, stats)?;
 
-    // filter init
-    let mut filter_ctx = FilterCtx::init_filter(&stream_ctx.dec_ctx, osd);
+    // pipeline is ready, the handler may answer now
+    stats.running(info.clone());
+    let _ = ready.send(info);
 
-    // time gap init
-    let mut time_gap = TimeGap::default();
+    // a failing loop still finalises the outputs before its error is returned
+    let mut reader = Reader::new(req, rx, stats, interrupt);
+    let result = remux_loop(&mut reader, &mut stream_ctx);
 
-    loop {
-        if let Ok(msg) = rx.try_recv() {
-            if msg.quit {
-                println!("system quit.");
-                break;
-            }
-        }
-        let mut packet = Packet::empty();
-        match packet.read(&mut fmt_ctx.in_fmt_ctx) {
-            Ok(_) => {}
-            Err(_) => {
-                continue;
-            }
-        }
-        if packet.size() == 0 {
-            continue;
-        }
+    // send EOF through the pipeline and finalise the output container
+    let StreamCtx { audio, fmt_ctx, .. } = &mut stream_ctx;
+    if let Err(e) = finish_output(audio, fmt_ctx, stats) {
+        log::warn!("finishing the outputs failed: {}", e);
+    }
+    result?;
+    match reader.finished {
+        true => stats.finished(),
+        false => stats.stopped(),
+    }
+    Ok(())
+}
 
-        let stream_idx = packet.stream();
+// decode, filter and encode every video packet until the session ends
+fn filter_loop(
+    reader: &mut Reader,
+    stream_ctx: &mut StreamCtx,
+    enc_ctx: &mut encoder::Video,
+    filter_ctx: &mut FilterCtx,
+) -> Result<()> {
+    let stats = reader.stats;
+    let StreamCtx {
+        dec_ctx,
+        de_frame,
+        stream_idx,
+        audio,
+        fmt_ctx,
+        ..
+    } = stream_ctx;
+    loop {
+        let packet = reader.next_packet(fmt_ctx, dec_ctx, *stream_idx, |target, cmd, arg| {
+            filter_ctx.send_command(target, cmd, arg)
+        })?;
+        let mut packet = match packet {
+            Some(packet) => packet,
+            None => return Ok(()),
+        };
 
-        if stream_idx == stream_ctx.stream_idx.0 as usize {
-            let in_stream = fmt_ctx.in_fmt_ctx.stream(stream_idx).unwrap();
-            packet.rescale_ts(in_stream.time_base(), stream_ctx.dec_ctx.time_base());
+        let packet_idx = packet.stream();
+        if packet_idx == stream_idx.0 as usize {
+            Stats::incr(&stats.packets_video);
+            let in_time_base = in_stream(&fmt_ctx.in_fmt_ctx, packet_idx)?.time_base();
+            packet.rescale_ts(in_time_base, dec_ctx.time_base());
 
-            // decode packet
-            match stream_ctx.dec_ctx.send_packet(&packet) {
-                Ok(()) => {
-                    // println!("send_packet success");
-                }
+            // decode packet, a full decoder hands out its frames before it takes the packet
+            let mut sent = dec_ctx.send_packet(&packet);
+            if matches!(&sent, Err(e) if is_full(e)) {
+                drain_decoder(dec_ctx, de_frame, enc_ctx, filter_ctx, fmt_ctx, stats)?;
+                sent = dec_ctx.send_packet(&packet);
+            }
+            match sent {
+                Ok(()) | Err(ffmpeg_next::Error::Eof) => {}
                 Err(_) => {
-                    // println!("send_packet failed:{}");
+                    Stats::incr(&stats.decode_errors);
+                    Stats::incr(&stats.frames_dropped);
                     continue;
                 }
             };
-            match stream_ctx.dec_ctx.receive_frame(&mut stream_ctx.de_frame) {
-                Ok(()) => {
-                    // println!("receive_frame success");
-                }
-                Err(_) => {
-                    // println!("receive_frame failed:{}");
-                    continue;
-                }
-            }
-            let best_timestamp = stream_ctx.de_frame.timestamp();
-            stream_ctx.de_frame.set_pts(best_timestamp);
-
-            filter_ctx.filter_encode_write_frame(
-                &mut stream_ctx.de_frame,
-                &mut stream_ctx.enc_ctx,
-                &mut fmt_ctx,
-                &mut time_gap,
-            );
+            drain_decoder(dec_ctx, de_frame, enc_ctx, filter_ctx, fmt_ctx, stats)?;
+            // keep a generated silent track up with the video
+            let clock = video_clock(&fmt_ctx.outputs);
+            let packets = silence_packets(audio, clock, stats);
+            write_audio(packets, fmt_ctx, stats)?;
         } else {
-            let out_fmt_timebase = fmt_ctx.out_fmt_ctx.stream(stream_idx).unwrap().time_base();
-            packet.rescale_ts(
-                fmt_ctx.in_fmt_ctx.stream(stream_idx).unwrap().time_base(),
-                out_fmt_timebase,
-            );
-
-            let audio_time: f64 = packet.pts().unwrap() as f64 * f64::from(out_fmt_timebase);
-            time_gap.audio_time = audio_time;
-            packet.write(&mut fmt_ctx.out_fmt_ctx).unwrap();
+            Stats::incr(&stats.packets_audio);
+            let packets = audio_packets(audio, packet, fmt_ctx, stats);
+            write_audio(packets, fmt_ctx, stats)?;
         }
     }
 }
 
-pub fn ffmtrans_remux(rx: Receiver<ThreadMsg>) {
-    // parse command line arguments
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 3 {
-        panic!("Usage: ./rtsp_to_rtmp <rtsp://stream-url> <rtmp://stream-url>");
+// copy every packet to the outputs until the session ends
+fn remux_loop(reader: &mut Reader, stream_ctx: &mut StreamCtx) -> Result<()> {
+    let stats = reader.stats;
+    let StreamCtx {
+        dec_ctx,
+        stream_idx,
+        audio,
+        fmt_ctx,
+        ..
+    } = stream_ctx;
+    loop {
+        let packet = reader.next_packet(fmt_ctx, dec_ctx, *stream_idx, |_, _, _| {
+            Err(Error::Filter(
+                "session has no filter graph",
+                ffmpeg_next::Error::FilterNotFound,
+            ))
+        })?;
+        let mut packet = match packet {
+            Some(packet) => packet,
+            None => return Ok(()),
+        };
+
+        // remux
+        let packet_idx = packet.stream();
+        if packet_idx == stream_idx.0 as usize {
+            Stats::incr(&stats.packets_video);
+            let in_time_base = in_stream(&fmt_ctx.in_fmt_ctx, packet_idx)?.time_base();
+            packet.set_stream(fmt_ctx.video_index);
+            write_all(
+                &mut fmt_ctx.outputs,
+                &packet,
+                in_time_base,
+                Type::Video,
+                stats,
+            )?;
+            // keep a generated silent track up with the video
+            let clock = video_clock(&fmt_ctx.outputs);
+            let packets = silence_packets(audio, clock, stats);
+            write_audio(packets, fmt_ctx, stats)?;
+        } else {
+            Stats::incr(&stats.packets_audio);
+            let packets = audio_packets(audio, packet, fmt_ctx, stats);
+            write_audio(packets, fmt_ctx, stats)?;
+        }
     }
-    let input_path = Path::new(&args[1]);
-    let output_url = Path::new(&args[2]);
+}
 
-    // init stream context
-    let mut options = Owned::new();
-    options.set("rtsp_transport", "tcp");
-    options.set("max_delay", "500");
-    let stream_ctx = StreamCtx::init(input_path, Some(options), output_url, "flv", None);
-    let mut fmt_ctx = stream_ctx.fmt_ctx;
+// input side of the worker loop shared by both pipelines: session messages,
+// read errors, the end of the input, reconnects, timestamps and pacing
+struct Reader<'a> {
+    req: &'a OSDReq,
+    rx: Receiver<ThreadMsg>,
+    stats: &'a Stats,
+    interrupt: &'a Interrupt,
+    // input clock, every output keeps its own output clock
+    sync: SyncCtx,
+    pacer: Pacer,
+    read_errors: u32,
+    // the input ended and the session is done
+    finished: bool,
+}
 
-    // write header
-    fmt_ctx
-        .out_fmt_ctx
-        .write_header()
-        .expect("Failed to write header");
+impl<'a> Reader<'a> {
+    fn new(
+        req: &'a OSDReq,
+        rx: Receiver<ThreadMsg>,
+        stats: &'a Stats,
+        interrupt: &'a Interrupt,
+    ) -> Self {
+        Reader {
+            req,
+            rx,
+            stats,
+            interrupt,
+            sync: SyncCtx::default(),
+            pacer: Pacer::default(),
+            read_errors: 0,
+            finished: false,
+        }
+    }
 
-    // time gap init
-    let mut time_gap = TimeGap::default();
+    // the next input packet on the session clock, None once the session ends.
+    // Filter commands that arrive meanwhile are answered by `command`.
+    fn next_packet<F>(
+        &mut self,
+        fmt_ctx: &mut FmtCtx,
+        dec_ctx: &mut decoder::Video,
+        stream_idx: (u32, Option<u32>),
+        mut command: F,
+    ) -> Result<Option<Packet>>
+    where
+        F: FnMut(&str, &str, &str) -> Result<String>,
+    {
+        loop {
+            // restart the I/O timeout for this packet
+            self.interrupt.arm();
+            match self.rx.try_recv() {
+                Ok(ThreadMsg::Quit) => {
+                    log::info!("system quit.");
+                    return Ok(None);
+                }
+                Ok(ThreadMsg::Command {
+                    target,
+                    cmd,
+                    arg,
+                    reply,
+                }) => {
+                    let _ = reply.send(command(&target, &cmd, &arg));
+                }
+                Err(_) => {}
+            }
 
-    loop {
-        if let Ok(msg) = rx.try_recv() {
-            if msg.quit {
-                println!("system quit.");
-                break;
+            let mut packet = Packet::empty();
+            match packet.read(&mut fmt_ctx.in_fmt_ctx) {
+                Ok(_) => self.read_errors = 0,
+                Err(e) => match self.read_failed(e, fmt_ctx, dec_ctx, stream_idx)? {
+                    true => continue,
+                    false => return Ok(None),
+                },
+            }
+            if packet.size() == 0 {
+                continue;
+            }
+            self.stats.touch();
+
+            let stream = in_stream(&fmt_ctx.in_fmt_ctx, packet.stream())?;
+            let session_us = self.sync.input(&mut packet, &stream);
+            if self.req.realtime {
+                if let Some(delay) = session_us.and_then(|t| self.pacer.delay(t)) {
+                    thread::sleep(delay);
+                }
             }
+            return Ok(Some(packet));
         }
+    }
+
+    // count a failed read, rewinding or reopening the input when it ended or
+    // was lost. False once the session ends.
+    fn read_failed(
+        &mut self,
+        e: ffmpeg_next::Error,
+        fmt_ctx: &mut FmtCtx,
+        dec_ctx: &mut decoder::Video,
+        stream_idx: (u32, Option<u32>),
+    ) -> Result<bool> {
+        self.read_errors += 1;
+        // the quit message is next in the channel
+        if self.interrupt.is_quit() {
+            return Ok(true);
+        }
+        match self.req.on_eof {
+            EofAction::Stop if e == ffmpeg_next::Error::Eof => {
+                log::info!("input finished.");
+                self.finished = true;
+                return Ok(false);
+            }
+            EofAction::Loop if e == ffmpeg_next::Error::Eof => {
+                // back to the start, the session clock keeps running
+                fmt_ctx
+                    .in_fmt_ctx
+                    .seek(0, ..)
+                    .map_err(|e| Error::Open(self.req.input.clone(), e))?;
+                self.sync.rebase(Rebase::Continue);
+                self.read_errors = 0;
+                return Ok(true);
+            }
+            _ => {}
+        }
+        if e == ffmpeg_next::Error::Eof
+            || self.interrupt.expired()
+            || self.read_errors >= self.req.input_retry.max_errors
+        {
+            log::warn!("input lost: {}", e);
+            let reopened = reopen_input(
+                self.req,
+                &self.rx,
+                self.stats,
+                self.interrupt,
+                fmt_ctx,
+                dec_ctx,
+                stream_idx,
+            )?;
+            if !reopened {
+                return Ok(false);
+            }
+            self.sync.rebase(Rebase::Continue);
+            self.pacer.reset();
+            self.read_errors = 0;
+        }
+        Ok(true)
+    }
+}
 
-        let mut packet = Packet::empty();
-        match packet.read(&mut fmt_ctx.in_fmt_ctx) {
-            Ok(_) => {}
+// a packet may hold several frames, or none until the decoder has enough input.
+// Push every frame the decoder has ready through filter graph and encoder.
+fn drain_decoder(
+    dec_ctx: &mut decoder::Video,
+    frame: &mut Video,
+    enc_ctx: &mut encoder::Video,
+    filter_ctx: &mut FilterCtx,
+    fmt_ctx: &mut FmtCtx,
+    stats: &Stats,
+) -> Result<()> {
+    loop {
+        match dec_ctx.receive_frame(frame) {
+            Ok(()) => {}
+            Err(e) if is_drained(&e) => return Ok(()),
             Err(_) => {
-                continue;
+                Stats::incr(&stats.decode_errors);
+                return Ok(());
             }
         }
-        if packet.size() == 0 {
-            continue;
+        Stats::incr(&stats.frames_decoded);
+        let best_timestamp = frame.timestamp();
+        frame.set_pts(best_timestamp);
+        filter_ctx.filter_encode_write_frame(frame, enc_ctx, fmt_ctx, stats)?;
+    }
+}
+
+// send EOF through decoder, filter graph and encoder, writing what comes out
+fn flush_video(
+    dec_ctx: &mut decoder::Video,
+    frame: &mut Video,
+    enc_ctx: &mut encoder::Video,
+    filter_ctx: &mut FilterCtx,
+    fmt_ctx: &mut FmtCtx,
+    stats: &Stats,
+) -> Result<()> {
+    dec_ctx
+        .send_eof()
+        .map_err(|e| Error::Codec("failed to flush decoder", e))?;
+    drain_decoder(dec_ctx, frame, enc_ctx, filter_ctx, fmt_ctx, stats)?;
+    filter_ctx.flush(enc_ctx, fmt_ctx, stats)
+}
+
+// flush the audio encoders and finalise every output container
+fn finish_output(audio: &mut [AudioCtx], fmt_ctx: &mut FmtCtx, stats: &Stats) -> Result<()> {
+    let mut result = Ok(());
+    // the trailers are written even when an audio encoder fails to flush
+    for audio in audio.iter_mut() {
+        let time_base = audio.time_base();
+        let flushed = audio
+            .flush(time_base, stats)
+            .and_then(|packets| write_audio((packets, time_base), fmt_ctx, stats));
+        if let Err(e) = flushed {
+            log::warn!("audio flush failed: {}", e);
+            result = Err(e);
+        }
+    }
+    for output in &mut fmt_ctx.outputs {
+        if let Err(e) = output.finish() {
+            log::warn!("output {} not finished: {}", output.conf.output, e);
+            result = Err(e);
         }
+    }
+    result
+}
 
-        let stream_idx = packet.stream();
+// audio packets for the muxer and their time base, transcoded when an
+// output container can't carry the input codec
+fn audio_packets(
+    audio: &mut [AudioCtx],
+    packet: Packet,
+    fmt_ctx: &FmtCtx,
+    stats: &Stats,
+) -> (Vec<Packet>, Rational) {
+    let stream_idx = packet.stream();
+    match audio.iter_mut().find(|a| a.stream_index == stream_idx) {
+        Some(audio) => {
+            let time_base = audio.time_base();
+            let packets = audio
+                .transcode(&packet, time_base, stats)
+                .unwrap_or_default();
+            (packets, time_base)
+        }
+        None => match fmt_ctx.in_fmt_ctx.stream(stream_idx) {
+            Some(in_stream) => (vec![packet], in_stream.time_base()),
+            None => (Vec::new(), Rational(1, 1)),
+        },
+    }
+}
 
-        // remux
-        if stream_idx == stream_ctx.stream_idx.0 as usize {
-            let in_stream = fmt_ctx.in_fmt_ctx.stream(stream_idx).unwrap();
-            packet.rescale_ts(in_stream.time_base(), stream_ctx.dec_ctx.time_base());
-            packet.set_stream(0);
-            let out_fmt_timebase = fmt_ctx.out_fmt_ctx.stream(0).unwrap().time_base();
-            unsafe {
-                packet.rescale_ts((*stream_ctx.enc_ctx.as_ptr()).time_base, out_fmt_timebase);
-            }
-            packet.set_dts(Some(
-                (time_gap.audio_time / f64::from(out_fmt_timebase)) as i64,
-            ));
-            packet.set_pts(Some(
-                (time_gap.audio_time / f64::from(out_fmt_timebase)) as i64,
+// a stream of the input, the demuxer only returns packets of known streams
+fn in_stream(in_fmt_ctx: &Input, index: usize) -> Result<Stream> {
+    in_fmt_ctx
+        .stream(index)
+        .ok_or_else(|| Error::Probe(format!("input stream {} not found", index)))
+}
+
+// silent audio up to the video clock, nothing unless the session generates it
+fn silence_packets(
+    audio: &mut [AudioCtx],
+    clock: Option<i64>,
+    stats: &Stats,
+) -> (Vec<Packet>, Rational) {
+    match (clock, audio.iter_mut().find(|a| a.is_silence())) {
+        (Some(clock), Some(audio)) => {
+            let time_base = audio.time_base();
+            let packets = audio
+                .fill_silence(clock, time_base, stats)
+                .unwrap_or_default();
+            (packets, time_base)
+        }
+        _ => (Vec::new(), Rational(1, 1)),
+    }
+}
+
+// write audio packets to every output
+fn write_audio(
+    (packets, time_base): (Vec<Packet>, Rational),
+    fmt_ctx: &mut FmtCtx,
+    stats: &Stats,
+) -> Result<()> {
+    for packet in packets {
+        write_all(&mut fmt_ctx.outputs, &packet, time_base, Type::Audio, stats)?;
+    }
+    Ok(())
+}
+
+// reopen a lost input with backoff, the output keeps running meanwhile.
+// Returns false if the session was closed while waiting.
+fn reopen_input(
+    req: &OSDReq,
+    rx: &Receiver<ThreadMsg>,
+    stats: &Stats,
+    interrupt: &Interrupt,
+    fmt_ctx: &mut FmtCtx,
+    dec_ctx: &mut decoder::Video,
+    stream_idx: (u32, Option<u32>),
+) -> Result<bool> {
+    stats.reconnecting();
+    let mut last_err = None;
+    for delay in req.input_retry.backoff() {
+        if !wait_or_quit(rx, delay) {
+            return Ok(false);
+        }
+        let (in_fmt_ctx, new_dec_ctx, new_stream_idx) =
+            match StreamCtx::input_open(Path::new(&req.input), req.in_dict(), interrupt) {
+                Ok(input) => input,
+                Err(e) => {
+                    log::warn!("reconnect {} failed: {}", req.input, e);
+                    last_err = Some(e);
+                    continue;
+                }
+            };
+        // output streams and filter graph were set up for the old input
+        if in_fmt_ctx.nb_streams() != fmt_ctx.in_fmt_ctx.nb_streams()
+            || new_stream_idx != stream_idx
+            || new_dec_ctx.width() != dec_ctx.width()
+            || new_dec_ctx.height() != dec_ctx.height()
+        {
+            return Err(Error::Probe(
+                "input layout changed after reconnect".to_string(),
             ));
-            let video_time: f64 = packet.pts().unwrap() as f64 * f64::from(out_fmt_timebase);
-            time_gap.video_time = video_time;
-            packet.write(&mut fmt_ctx.out_fmt_ctx).unwrap();
-        } else {
-            let out_fmt_timebase = fmt_ctx.out_fmt_ctx.stream(stream_idx).unwrap().time_base();
-            packet.rescale_ts(
-                fmt_ctx.in_fmt_ctx.stream(stream_idx).unwrap().time_base(),
-                out_fmt_timebase,
-            );
-
-            let audio_time: f64 = packet.pts().unwrap() as f64 * f64::from(out_fmt_timebase);
-            time_gap.audio_time = audio_time;
-            packet.write(&mut fmt_ctx.out_fmt_ctx).unwrap();
+        }
+        fmt_ctx.in_fmt_ctx = in_fmt_ctx;
+        *dec_ctx = new_dec_ctx;
+        Stats::incr(&stats.input_reconnects);
+        stats.resumed();
+        return Ok(true);
+    }
+    Err(last_err.unwrap_or_else(|| Error::Probe("input reconnect disabled".to_string())))
+}
+
+// sleep while still answering the session channel, false on quit
+fn wait_or_quit(rx: &Receiver<ThreadMsg>, delay: Duration) -> bool {
+    let deadline = Instant::now() + delay;
+    loop {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        match rx.recv_timeout(timeout) {
+            Ok(ThreadMsg::Quit) | Err(RecvTimeoutError::Disconnected) => return false,
+            Ok(ThreadMsg::Command { reply, .. }) => {
+                let _ = reply.send(Err(Error::Probe("session is reconnecting".to_string())));
+            }
+            Err(RecvTimeoutError::Timeout) => return true,
         }
     }
 }
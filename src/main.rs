@@ -2,30 +2,72 @@ use std::io;
 
 use actix_cors::Cors;
 use actix_web::{http, web, App, HttpResponse, HttpServer};
-use ffmtrans::serve::route::{close_handler, trans_handler, ThreadChannel};
+use ffmtrans::serve::{
+    route::{
+        close_handler, hls_handler, json_error, metrics_handler, osd_cmd_handler,
+        session_status_handler, status_handler, trans_handler, upload_handler,
+    },
+    session::SessionMap,
+};
+use log::{Level, LevelFilter, Log, Metadata, Record};
 
 async fn preflight() -> io::Result<HttpResponse> {
     Ok(HttpResponse::Ok().finish())
 }
 
+// session events on stdout
+struct Logger;
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= Level::Info
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            println!("[{}] {}", record.level(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: Logger = Logger;
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    // thread controller
-    let thread_channel = web::Data::new(ThreadChannel::new());
+    // only fails if a logger is already set
+    let _ = log::set_logger(&LOGGER).map(|()| log::set_max_level(LevelFilter::Info));
+    // session registry
+    let session_map = web::Data::new(SessionMap::new());
     // route init
     HttpServer::new(move || {
         App::new()
             .wrap(
                 Cors::default()
                     .allow_any_origin()
-                    .allowed_methods(vec!["POST", "OPTIONS"])
+                    .allowed_methods(vec!["GET", "POST", "OPTIONS"])
                     .allow_any_header()
                     .max_age(3600),
             )
-            .app_data(thread_channel.clone())
+            .app_data(session_map.clone())
+            .app_data(web::JsonConfig::default().error_handler(json_error))
             .route("/setosd", web::post().to(trans_handler))
             .route("/setosd", web::method(http::Method::OPTIONS).to(preflight))
+            .route("/osdcmd", web::post().to(osd_cmd_handler))
+            .route("/osdcmd", web::method(http::Method::OPTIONS).to(preflight))
+            .service(
+                web::resource("/upload/{name}")
+                    // images are larger than the default payload limit
+                    .app_data(web::PayloadConfig::new(8 * 1024 * 1024))
+                    .route(web::post().to(upload_handler))
+                    .route(web::method(http::Method::OPTIONS).to(preflight)),
+            )
             .route("/close", web::get().to(close_handler))
+            .route("/status", web::get().to(status_handler))
+            .route("/status/{id}", web::get().to(session_status_handler))
+            .route("/metrics", web::get().to(metrics_handler))
+            .route("/hls/{id}/{name}", web::get().to(hls_handler))
     })
     .bind(("127.0.0.1", 3000))?
     .run()
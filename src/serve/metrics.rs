@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use super::session::Session;
+use crate::trans::stats::Status;
+
+type Getter = fn(&Status) -> f64;
+
+struct Metric {
+    name: &'static str,
+    kind: &'static str,
+    help: &'static str,
+    // extra labels, value
+    series: &'static [(&'static str, Getter)],
+}
+
+const METRICS: &[Metric] = &[
+    Metric {
+        name: "ffmtrans_up",
+        kind: "gauge",
+        help: "Whether the session worker is alive.",
+        series: &[("", |s| s.state.is_alive() as u8 as f64)],
+    },
+    Metric {
+        name: "ffmtrans_uptime_seconds",
+        kind: "gauge",
+        help: "Seconds since the session worker started.",
+        series: &[("", |s| s.uptime)],
+    },
+    Metric {
+        name: "ffmtrans_packets_read_total",
+        kind: "counter",
+        help: "Packets read from the input.",
+        series: &[
+            ("stream=\"video\"", |s| s.packets_video as f64),
+            ("stream=\"audio\"", |s| s.packets_audio as f64),
+        ],
+    },
+    Metric {
+        name: "ffmtrans_frames_decoded_total",
+        kind: "counter",
+        help: "Video frames decoded.",
+        series: &[("", |s| s.frames_decoded as f64)],
+    },
+    Metric {
+        name: "ffmtrans_frames_filtered_total",
+        kind: "counter",
+        help: "Video frames pulled from the filter graph.",
+        series: &[("", |s| s.frames_filtered as f64)],
+    },
+    Metric {
+        name: "ffmtrans_frames_encoded_total",
+        kind: "counter",
+        help: "Video packets produced by the encoder.",
+        series: &[("", |s| s.frames_encoded as f64)],
+    },
+    Metric {
+        name: "ffmtrans_decode_errors_total",
+        kind: "counter",
+        help: "Decoder errors skipped by the worker loop.",
+        series: &[("", |s| s.decode_errors as f64)],
+    },
+    Metric {
+        name: "ffmtrans_encode_errors_total",
+        kind: "counter",
+        help: "Encoder errors skipped by the worker loop.",
+        series: &[("", |s| s.encode_errors as f64)],
+    },
+    Metric {
+        name: "ffmtrans_output_bytes_total",
+        kind: "counter",
+        help: "Bytes handed to the output muxer.",
+        series: &[("", |s| s.bytes_written as f64)],
+    },
+    Metric {
+        name: "ffmtrans_av_drift_seconds",
+        kind: "gauge",
+        help: "Audio clock minus video clock.",
+        series: &[("", |s| s.drift)],
+    },
+    Metric {
+        name: "ffmtrans_input_reconnects_total",
+        kind: "counter",
+        help: "Times the input was reopened after a loss.",
+        series: &[("", |s| s.input_reconnects as f64)],
+    },
+    Metric {
+        name: "ffmtrans_output_reconnects_total",
+        kind: "counter",
+        help: "Times an output was reopened after a write failure.",
+        series: &[("", |s| s.output_reconnects as f64)],
+    },
+    Metric {
+        name: "ffmtrans_worker_restarts_total",
+        kind: "counter",
+        help: "Times the session worker was restarted.",
+        series: &[("", |s| s.restarts as f64)],
+    },
+];
+
+// prometheus text exposition format
+pub fn render(sessions: &HashMap<String, Session>) -> String {
+    let mut status: Vec<(String, Status)> = sessions
+        .iter()
+        .map(|(id, session)| (id.clone(), session.stats.status()))
+        .collect();
+    // stable series order between scrapes
+    status.sort_by(|a, b| a.0.cmp(&b.0));
+    render_status(&status)
+}
+
+fn render_status(status: &[(String, Status)]) -> String {
+    let mut out = String::new();
+    for metric in METRICS {
+        writeln!(out, "# HELP {} {}", metric.name, metric.help).unwrap();
+        writeln!(out, "# TYPE {} {}", metric.name, metric.kind).unwrap();
+        for (id, status) in status {
+            let id = escape_label(id);
+            for (labels, value) in metric.series {
+                let sep = if labels.is_empty() { "" } else { "," };
+                writeln!(
+                    out,
+                    "{}{{session=\"{}\"{}{}}} {}",
+                    metric.name,
+                    id,
+                    sep,
+                    labels,
+                    value(status)
+                )
+                .unwrap();
+            }
+        }
+    }
+    out
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trans::stats::Stats;
+
+    #[test]
+    fn render_labels_every_session() {
+        let stats = Stats::default();
+        for _ in 0..7 {
+            Stats::incr(&stats.packets_video);
+        }
+        let mut status = stats.status();
+        status.uptime = 12.5;
+        let out = render_status(&[
+            ("a".to_string(), Stats::default().status()),
+            ("cam\"1".to_string(), status),
+        ]);
+        assert!(out.contains("# TYPE ffmtrans_packets_read_total counter\n"));
+        assert!(
+            out.contains("ffmtrans_packets_read_total{session=\"cam\\\"1\",stream=\"video\"} 7\n")
+        );
+        assert!(out.contains("ffmtrans_uptime_seconds{session=\"cam\\\"1\"} 12.5\n"));
+        let a = out.find("ffmtrans_up{session=\"a\"}").unwrap();
+        let cam = out.find("ffmtrans_up{session=\"cam").unwrap();
+        assert!(a < cam);
+    }
+
+    #[test]
+    fn escape_label_quotes_and_newlines() {
+        assert_eq!(escape_label("a\\b\"c\nd"), r#"a\\b\"c\nd"#);
+    }
+}
@@ -1 +1,3 @@
+pub mod metrics;
 pub mod route;
+pub mod session;
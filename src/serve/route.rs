@@ -1,69 +1,412 @@
+use actix_web::error::{InternalError, JsonPayloadError};
+use actix_web::http::{header, StatusCode};
 use actix_web::web::Data;
-use actix_web::{web, HttpResponse};
-use crossbeam_channel::{unbounded, Receiver, Sender};
-use serde::Deserialize;
-use std::sync::{Arc, Mutex};
-use std::thread::{self, JoinHandle};
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
+use crossbeam_channel::{bounded, Sender};
+use ffmpeg_next::dictionary::Owned;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 
+use super::metrics;
+use super::session::{panic_message, Session, SessionMap};
+use crate::error::{Error, Result};
+use crate::trans::{
+    container::Container,
+    encoder::EncoderConf,
+    ffmpeg::StreamInfo,
+    filter::{escape_value, literal_text, FilterCtx},
+    hls,
+    interrupt::Interrupt,
+    osd::{Osd, UPLOAD_DIR},
+    output::OutputConf,
+    reconnect::RetryConf,
+    source::EofAction,
+    stats::Stats,
+};
 use crate::{ffmtrans_remux, ffmtrans_with_filter};
 
 #[derive(Deserialize, Debug)]
 pub struct OSDReq {
-    osd: String,
+    pub id: String,
+    #[serde(default)]
+    pub osd: Osd,
+    pub input: String,
+    // output, format, out_options and output_retry of the first output
+    #[serde(flatten)]
+    pub primary: OutputConf,
+    // further outputs fed by the same decode and encode
+    #[serde(default)]
+    pub outputs: Vec<OutputConf>,
+    #[serde(default = "default_in_options")]
+    pub in_options: HashMap<String, String>,
+    #[serde(default)]
+    pub encoder: EncoderConf,
+    #[serde(default)]
+    pub input_retry: RetryConf,
+    // what to do when the input ends, files usually "stop" or "loop"
+    #[serde(default)]
+    pub on_eof: EofAction,
+    // read no faster than real time
+    #[serde(default)]
+    pub realtime: bool,
+    // blocking input/output calls are aborted after this long
+    #[serde(default = "default_io_timeout")]
+    pub io_timeout_ms: u64,
 }
 
-pub struct ThreadMsg {
-    pub quit: bool,
+fn default_io_timeout() -> u64 {
+    10_000
 }
 
-#[derive(Clone)]
-pub struct ThreadChannel {
-    pub tx: Sender<ThreadMsg>,
-    pub rx: Receiver<ThreadMsg>,
-    pub pre_thread: Arc<Mutex<Option<JoinHandle<()>>>>,
+fn default_in_options() -> HashMap<String, String> {
+    HashMap::from([
+        ("rtsp_transport".to_string(), "tcp".to_string()),
+        ("max_delay".to_string(), "500".to_string()),
+    ])
 }
-impl ThreadChannel {
-    pub fn new() -> Self {
-        // pre_thread init
-        let pre_thread: Arc<Mutex<Option<JoinHandle<()>>>> = Arc::new(Mutex::new(None));
-        // message
-        let (tx, rx): (Sender<ThreadMsg>, Receiver<ThreadMsg>) = unbounded();
-        // threadChannel
-        ThreadChannel { tx, rx, pre_thread }
+
+impl OSDReq {
+    pub fn in_dict(&self) -> Option<Owned<'static>> {
+        to_dict(&self.in_options)
+    }
+
+    pub fn outputs(&self) -> Vec<OutputConf> {
+        std::iter::once(&self.primary)
+            .chain(&self.outputs)
+            .cloned()
+            .collect()
+    }
+
+    // remux unless there is an OSD, the video is scaled or an output aligns
+    // its segments to the GOP
+    pub fn needs_filter(&self) -> bool {
+        !self.osd.is_empty()
+            || self.encoder.needs_filter()
+            || self
+                .outputs()
+                .iter()
+                .any(|output| output.segment_time().is_some())
+    }
+}
+
+fn to_dict(options: &HashMap<String, String>) -> Option<Owned<'static>> {
+    if options.is_empty() {
+        return None;
+    }
+    let mut dict = Owned::new();
+    for (key, value) in options {
+        dict.set(key, value);
+    }
+    Some(dict)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CloseReq {
+    id: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Resp<T: Serialize> {
+    pub code: u16,
+    pub msg: String,
+    pub data: Option<T>,
+}
+
+impl<T: Serialize> Resp<T> {
+    pub fn ok(data: T) -> HttpResponse {
+        HttpResponse::Ok().json(Resp {
+            code: 200,
+            msg: "ok".to_string(),
+            data: Some(data),
+        })
+    }
+}
+
+impl Resp<()> {
+    pub fn err(status: StatusCode, msg: String) -> HttpResponse {
+        HttpResponse::build(status).json(Resp::<()> {
+            code: status.as_u16(),
+            msg,
+            data: None,
+        })
+    }
+}
+
+fn error_status(err: &Error) -> StatusCode {
+    match err {
+        Error::Filter(..) | Error::Config(_) => StatusCode::BAD_REQUEST,
+        Error::Open(..) | Error::Probe(_) | Error::Mux(..) => StatusCode::BAD_GATEWAY,
+        Error::Codec(..) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+// a body that doesn't parse is answered like any other error
+pub fn json_error(err: JsonPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    let resp = Resp::err(err.status_code(), err.to_string());
+    InternalError::from_response(err, resp).into()
+}
+
+#[derive(Deserialize, Debug)]
+pub struct OSDCmdReq {
+    id: String,
+    // filter name or instance name, "all" for every filter
+    #[serde(default = "default_target")]
+    target: String,
+    #[serde(default = "default_cmd")]
+    cmd: String,
+    // shorthand for the drawtext "text" option
+    text: Option<String>,
+    #[serde(default)]
+    params: BTreeMap<String, String>,
+}
+
+fn default_target() -> String {
+    "drawtext".to_string()
+}
+
+fn default_cmd() -> String {
+    "reinit".to_string()
+}
+
+impl OSDCmdReq {
+    fn arg(&self) -> String {
+        let text = self.text.iter().map(|text| ("text", literal_text(text)));
+        let params = self.params.iter().map(|(k, v)| (k.as_str(), v.clone()));
+        text.chain(params)
+            .map(|(key, value)| format!("{}={}", key, escape_value(&value, false)))
+            .collect::<Vec<_>>()
+            .join(":")
     }
 }
 
-pub async fn trans_handler(data: Data<ThreadChannel>, body: web::Json<OSDReq>) -> HttpResponse {
-    let mut thread_guard = data.pre_thread.lock().unwrap();
+pub enum ThreadMsg {
+    Quit,
+    // forwarded to avfilter_graph_send_command, answered on `reply`
+    Command {
+        target: String,
+        cmd: String,
+        arg: String,
+        reply: Sender<Result<String>>,
+    },
+}
 
-    if let Some(pre_thread) = thread_guard.take() {
-        data.tx
-            .send(ThreadMsg { quit: true })
-            .expect("send failed!!");
-        pre_thread.join().unwrap();
+pub async fn trans_handler(data: Data<SessionMap>, body: web::Json<OSDReq>) -> HttpResponse {
+    let body = body.into_inner();
+
+    // refuse a bad OSD before a running session is stopped for it
+    if body.needs_filter() {
+        let (osd, encoder) = (body.osd.to_filter(), body.encoder.clone());
+        let checked = web::block(move || FilterCtx::check(&osd, &encoder))
+            .await
+            .unwrap();
+        if let Err(e) = checked {
+            return Resp::err(error_status(&e), e.to_string());
+        }
     }
 
-    // get rx clone
-    let rx = data.rx.clone();
+    let stats = Arc::new(Stats::default());
+    let interrupt = Arc::new(Interrupt::new(body.io_timeout_ms));
 
-    let new_thread = thread::spawn(move || match &body.osd.len() {
-        0 => ffmtrans_remux(rx),
-        _ => ffmtrans_with_filter(&body.osd, rx),
+    // update: stop the previous worker of this session
+    let pre_session = data.sessions.lock().unwrap().remove(&body.id);
+    if let Some(pre_session) = pre_session {
+        let restarts = pre_session.stats.status().restarts;
+        stats.restarts.store(restarts + 1, Ordering::Relaxed);
+        web::block(move || pre_session.stop()).await.unwrap();
+    }
+
+    // session channel
+    let (tx, rx) = Session::channel();
+    let (ready_tx, ready_rx) = bounded::<StreamInfo>(1);
+
+    let id = body.id.clone();
+    let hls = body
+        .outputs()
+        .into_iter()
+        .find(|output| output.container() == Container::Hls)
+        .map(|output| PathBuf::from(output.output));
+    let thread_stats = stats.clone();
+    let thread_interrupt = interrupt.clone();
+    let thread = thread::spawn(move || {
+        let (stats, interrupt) = (&thread_stats, &thread_interrupt);
+        let result = match body.needs_filter() {
+            false => ffmtrans_remux(&body, rx, ready_tx, stats, interrupt),
+            true => ffmtrans_with_filter(&body, rx, ready_tx, stats, interrupt),
+        };
+        if let Err(e) = &result {
+            log::error!("session {} stopped: {}", body.id, e);
+            thread_stats.failed(e.to_string());
+        }
+        result
     });
 
-    *thread_guard = Some(new_thread);
-    HttpResponse::Ok().body("ok")
+    // wait until the input is probed, the encoder opened and the filter validated
+    let ready = web::block(move || match ready_rx.recv() {
+        Ok(info) => Ok((info, thread)),
+        // sender dropped: the worker failed during setup
+        Err(_) => Err(match thread.join() {
+            Ok(result) => result.err().map(|e| (error_status(&e), e.to_string())),
+            Err(panic) => Some((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("worker panicked: {}", panic_message(&*panic)),
+            )),
+        }),
+    })
+    .await
+    .unwrap();
+
+    match ready {
+        Ok((info, thread)) => {
+            let session = Session {
+                tx,
+                thread,
+                stats,
+                interrupt,
+                hls,
+            };
+            let replaced = data.sessions.lock().unwrap().insert(id, session);
+            if let Some(replaced) = replaced {
+                web::block(move || replaced.stop()).await.unwrap();
+            }
+            Resp::ok(info)
+        }
+        Err(Some((status, msg))) => Resp::err(status, msg),
+        Err(None) => Resp::err(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "worker exited during setup".to_string(),
+        ),
+    }
 }
 
-pub async fn close_handler(data: Data<ThreadChannel>) -> HttpResponse {
-    let mut thread_guard = data.pre_thread.lock().unwrap();
+pub async fn close_handler(data: Data<SessionMap>, query: web::Query<CloseReq>) -> HttpResponse {
+    let session = data.sessions.lock().unwrap().remove(&query.id);
 
-    if let Some(pre_thread) = thread_guard.take() {
-        data.tx
-            .send(ThreadMsg { quit: true })
-            .expect("send failed!!");
-        pre_thread.join().unwrap();
+    match session {
+        Some(session) => {
+            web::block(move || session.stop()).await.unwrap();
+            Resp::ok(())
+        }
+        None => Resp::err(StatusCode::NOT_FOUND, "session not found".to_string()),
     }
-    *thread_guard = None;
-    HttpResponse::Ok().body("ok")
+}
+
+pub async fn osd_cmd_handler(data: Data<SessionMap>, body: web::Json<OSDCmdReq>) -> HttpResponse {
+    let tx = match data.sessions.lock().unwrap().get(&body.id) {
+        Some(session) => session.tx.clone(),
+        None => return Resp::err(StatusCode::NOT_FOUND, "session not found".to_string()),
+    };
+
+    let (reply_tx, reply_rx) = bounded(1);
+    let msg = ThreadMsg::Command {
+        target: body.target.clone(),
+        cmd: body.cmd.clone(),
+        arg: body.arg(),
+        reply: reply_tx,
+    };
+    if tx.send(msg).is_err() {
+        return Resp::err(StatusCode::GONE, "session worker exited".to_string());
+    }
+
+    // the worker answers between two packets
+    let reply = web::block(move || reply_rx.recv_timeout(Duration::from_secs(5)))
+        .await
+        .unwrap();
+    match reply {
+        Ok(Ok(res)) => Resp::ok(res),
+        Ok(Err(e)) => Resp::err(error_status(&e), e.to_string()),
+        Err(_) => Resp::err(
+            StatusCode::GATEWAY_TIMEOUT,
+            "session worker did not answer".to_string(),
+        ),
+    }
+}
+
+// store an image for `{"image": {"upload": name}}` overlays
+pub async fn upload_handler(name: web::Path<String>, body: web::Bytes) -> HttpResponse {
+    if !valid_file_name(&name) {
+        return Resp::err(StatusCode::BAD_REQUEST, "invalid upload name".to_string());
+    }
+
+    let result = web::block(move || {
+        fs::create_dir_all(UPLOAD_DIR)?;
+        fs::write(Path::new(UPLOAD_DIR).join(name.as_str()), &body)
+    })
+    .await
+    .unwrap();
+    match result {
+        Ok(()) => Resp::ok(()),
+        Err(e) => Resp::err(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    }
+}
+
+fn valid_file_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+}
+
+// live playlist and segments of a session's HLS output
+pub async fn hls_handler(
+    data: Data<SessionMap>,
+    path: web::Path<(String, String)>,
+) -> HttpResponse {
+    let (id, name) = path.into_inner();
+    let playlist = match data.sessions.lock().unwrap().get(&id) {
+        Some(session) => session.hls.clone(),
+        None => return Resp::err(StatusCode::NOT_FOUND, "session not found".to_string()),
+    };
+    let file = playlist
+        .filter(|_| valid_file_name(&name))
+        .and_then(|playlist| hls::file_path(&playlist, &name))
+        .zip(hls::content_type(&name));
+    let (file, (content_type, cache_control)) = match file {
+        Some(file) => file,
+        None => return Resp::err(StatusCode::NOT_FOUND, "file not found".to_string()),
+    };
+
+    let body = web::block(move || fs::read(file)).await.unwrap();
+    match body {
+        Ok(body) => HttpResponse::Ok()
+            .content_type(content_type)
+            .insert_header((header::CACHE_CONTROL, cache_control))
+            .body(body),
+        // not written yet or already deleted
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            Resp::err(StatusCode::NOT_FOUND, "file not found".to_string())
+        }
+        Err(e) => Resp::err(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    }
+}
+
+pub async fn status_handler(data: Data<SessionMap>) -> HttpResponse {
+    let sessions = data.sessions.lock().unwrap();
+    let status: BTreeMap<_, _> = sessions
+        .iter()
+        .map(|(id, session)| (id.clone(), session.stats.status()))
+        .collect();
+    Resp::ok(status)
+}
+
+pub async fn session_status_handler(data: Data<SessionMap>, id: web::Path<String>) -> HttpResponse {
+    let sessions = data.sessions.lock().unwrap();
+    match sessions.get(id.as_str()) {
+        Some(session) => Resp::ok(session.stats.status()),
+        None => Resp::err(StatusCode::NOT_FOUND, "session not found".to_string()),
+    }
+}
+
+pub async fn metrics_handler(data: Data<SessionMap>) -> HttpResponse {
+    let sessions = data.sessions.lock().unwrap();
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics::render(&sessions))
 }
@@ -0,0 +1,57 @@
+use crossbeam_channel::{unbounded, Receiver, Sender};
+use std::any::Any;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+use super::route::ThreadMsg;
+use crate::error::Result;
+use crate::trans::{interrupt::Interrupt, stats::Stats};
+
+pub struct Session {
+    pub tx: Sender<ThreadMsg>,
+    pub thread: JoinHandle<Result<()>>,
+    pub stats: Arc<Stats>,
+    pub interrupt: Arc<Interrupt>,
+    // playlist of the first HLS output, served under /hls/{id}/
+    pub hls: Option<PathBuf>,
+}
+
+impl Session {
+    pub fn channel() -> (Sender<ThreadMsg>, Receiver<ThreadMsg>) {
+        unbounded()
+    }
+
+    pub fn stop(self) {
+        // abort a blocking input read so the worker sees the message
+        self.interrupt.quit();
+        // worker may already be gone, ignore send error
+        let _ = self.tx.send(ThreadMsg::Quit);
+        // the worker already reported its own error, a panic has to be reported here
+        if let Err(panic) = self.thread.join() {
+            self.stats
+                .failed(format!("worker panicked: {}", panic_message(&*panic)));
+        }
+    }
+}
+
+// the payload of a panic!, a &str or a String
+pub fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    match (panic.downcast_ref::<&str>(), panic.downcast_ref::<String>()) {
+        (Some(msg), _) => msg,
+        (_, Some(msg)) => msg,
+        _ => "unknown panic",
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct SessionMap {
+    pub sessions: Arc<Mutex<HashMap<String, Session>>>,
+}
+
+impl SessionMap {
+    pub fn new() -> Self {
+        SessionMap::default()
+    }
+}
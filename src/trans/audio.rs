@@ -0,0 +1,495 @@
+use ffmpeg_next::{
+    codec::{self, Context},
+    decoder, encoder,
+    filter::{self, Graph},
+    format::{self, stream::Stream, stream::StreamMut, Sample},
+    frame, ChannelLayout, Codec, Packet, Rational, Rescale,
+};
+use ffmpeg_sys_next::{
+    avcodec_parameters_from_context, avformat_query_codec, FF_COMPLIANCE_NORMAL,
+};
+use serde::Deserialize;
+
+use super::{filter::graph_filter, stats::Stats, sync::US};
+use crate::error::{is_drained, is_full, Error, Result};
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioMode {
+    // transcode only if the input codec does not fit the output container
+    #[default]
+    Auto,
+    Copy,
+    Transcode,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Layout {
+    Mono,
+    Stereo,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct AudioConf {
+    #[serde(default)]
+    pub mode: AudioMode,
+    #[serde(default = "default_codec")]
+    pub codec: String,
+    // defaults to the input rate, or the closest rate the encoder supports
+    pub sample_rate: Option<u32>,
+    // defaults to the input layout, downmixed to stereo
+    pub channel_layout: Option<Layout>,
+    // kbit/s
+    #[serde(default = "default_bitrate")]
+    pub bitrate: usize,
+    // add a silent track when the input has no audio, otherwise the output is video only
+    #[serde(default)]
+    pub silence: bool,
+}
+
+fn default_codec() -> String {
+    "aac".to_string()
+}
+
+fn default_bitrate() -> usize {
+    128
+}
+
+impl Default for AudioConf {
+    fn default() -> Self {
+        AudioConf {
+            mode: AudioMode::default(),
+            codec: default_codec(),
+            sample_rate: None,
+            channel_layout: None,
+            bitrate: default_bitrate(),
+            silence: false,
+        }
+    }
+}
+
+impl AudioConf {
+    pub fn needs_transcode(&self, out_fmt: &format::Output, codec_id: codec::Id) -> bool {
+        match self.mode {
+            AudioMode::Copy => false,
+            AudioMode::Transcode => true,
+            AudioMode::Auto => !playable(out_fmt, codec_id),
+        }
+    }
+
+    fn output_rate(&self, codec: &codec::Audio, in_rate: u32) -> u32 {
+        let rate = self.sample_rate.unwrap_or(in_rate);
+        match codec.rates() {
+            Some(rates) => rates
+                .map(|r| r as u32)
+                .min_by_key(|r| r.abs_diff(rate))
+                .unwrap_or(rate),
+            None => rate,
+        }
+    }
+
+    fn output_layout(&self, in_layout: ChannelLayout) -> ChannelLayout {
+        match self.channel_layout {
+            Some(Layout::Mono) => ChannelLayout::MONO,
+            Some(Layout::Stereo) => ChannelLayout::STEREO,
+            None if in_layout.channels() <= 2 => in_layout,
+            None => ChannelLayout::STEREO,
+        }
+    }
+}
+
+// codecs players accept in the container, muxers often take more than that,
+// e.g. flv muxes G.711 but nothing plays it
+fn playable(out_fmt: &format::Output, codec_id: codec::Id) -> bool {
+    use codec::Id;
+    let codecs: &[Id] = match out_fmt.name() {
+        "flv" => &[Id::AAC, Id::MP3],
+        "mp4" | "mov" | "ipod" => &[Id::AAC, Id::MP3, Id::AC3, Id::EAC3, Id::OPUS, Id::ALAC],
+        "mpegts" | "hls" => &[Id::AAC, Id::MP3, Id::MP2, Id::AC3, Id::EAC3, Id::OPUS],
+        _ => unsafe {
+            return avformat_query_codec(out_fmt.as_ptr(), codec_id.into(), FF_COMPLIANCE_NORMAL)
+                == 1;
+        },
+    };
+    codecs.contains(&codec_id)
+}
+
+// decoder -> abuffer/abuffersink (resample, frame size) -> encoder for one audio stream,
+// or anullsrc -> abuffersink -> encoder for a silent track
+pub struct AudioCtx {
+    pub stream_index: usize,
+    dec_ctx: Option<decoder::Audio>,
+    enc_ctx: encoder::Audio,
+    filter_graph: Graph,
+    de_frame: frame::Audio,
+    filter_frame: frame::Audio,
+    // end of the silence generated so far in the encoder time base, None until
+    // the first video packet
+    next_pts: Option<i64>,
+}
+
+impl AudioCtx {
+    pub fn open(in_stream: &Stream, conf: &AudioConf, global_header: bool) -> Result<Self> {
+        let codec_ctx = Context::from_parameters(in_stream.parameters())
+            .map_err(|e| Error::Codec("failed to copy audio decoder parameters", e))?;
+        let mut dec_ctx = codec_ctx
+            .decoder()
+            .audio()
+            .map_err(|e| Error::Codec("failed to open audio decoder", e))?;
+        unsafe {
+            (*dec_ctx.as_mut_ptr()).pkt_timebase = in_stream.time_base().into();
+        }
+        let in_layout = match dec_ctx.channel_layout() {
+            layout if layout.is_empty() => ChannelLayout::default(dec_ctx.channels() as i32),
+            layout => layout,
+        };
+        let (codec, enc_ctx) = open_encoder(conf, dec_ctx.rate(), in_layout, global_header)?;
+
+        // abuffer -> anull -> abuffersink, the graph inserts the resampler
+        let time_base = in_stream.time_base();
+        let args = format!(
+            "time_base={}/{}:sample_rate={}:sample_fmt={}:channel_layout=0x{:x}",
+            time_base.numerator(),
+            time_base.denominator(),
+            dec_ctx.rate(),
+            dec_ctx.format().name(),
+            in_layout.bits(),
+        );
+        let filter_graph = open_graph("abuffer", &args, &codec, &enc_ctx)?;
+
+        Ok(AudioCtx {
+            stream_index: in_stream.index(),
+            dec_ctx: Some(dec_ctx),
+            enc_ctx,
+            filter_graph,
+            de_frame: frame::Audio::empty(),
+            filter_frame: frame::Audio::empty(),
+            next_pts: None,
+        })
+    }
+
+    // silent track for inputs without audio, written to output stream `stream_index`
+    pub fn silence(stream_index: usize, conf: &AudioConf, global_header: bool) -> Result<Self> {
+        let (codec, enc_ctx) = open_encoder(conf, 44100, ChannelLayout::MONO, global_header)?;
+        let args = format!(
+            "sample_rate={}:channel_layout=0x{:x}",
+            enc_ctx.rate(),
+            enc_ctx.channel_layout().bits(),
+        );
+        let filter_graph = open_graph("anullsrc", &args, &codec, &enc_ctx)?;
+        Ok(AudioCtx {
+            stream_index,
+            dec_ctx: None,
+            enc_ctx,
+            filter_graph,
+            de_frame: frame::Audio::empty(),
+            filter_frame: frame::Audio::empty(),
+            next_pts: None,
+        })
+    }
+
+    // out stream parameters from the encoder
+    pub fn set_parameters(&self, out_stream: &mut StreamMut) {
+        unsafe {
+            avcodec_parameters_from_context(
+                (*out_stream.as_mut_ptr()).codecpar,
+                self.enc_ctx.as_ptr(),
+            );
+            (*out_stream.as_mut_ptr()).time_base = (*self.enc_ctx.as_ptr()).time_base;
+        }
+    }
+
+    // encoded packets come out in this time base
+    pub fn time_base(&self) -> Rational {
+        unsafe { (*self.enc_ctx.as_ptr()).time_base.into() }
+    }
+
+    pub fn is_silence(&self) -> bool {
+        self.dec_ctx.is_none()
+    }
+
+    // decode an input packet and return the encoded packets in the output time base
+    pub fn transcode(
+        &mut self,
+        packet: &Packet,
+        out_time_base: Rational,
+        stats: &Stats,
+    ) -> Result<Vec<Packet>> {
+        let mut packets = Vec::new();
+        let mut sent = match &mut self.dec_ctx {
+            Some(dec_ctx) => dec_ctx.send_packet(packet),
+            None => return Ok(packets),
+        };
+        // a full decoder hands out its frames before it takes the packet
+        if matches!(&sent, Err(e) if is_full(e)) {
+            self.decode(out_time_base, stats, &mut packets)?;
+            if let Some(dec_ctx) = &mut self.dec_ctx {
+                sent = dec_ctx.send_packet(packet);
+            }
+        }
+        match sent {
+            Ok(()) | Err(ffmpeg_next::Error::Eof) => {}
+            Err(e) => {
+                Stats::incr(&stats.decode_errors);
+                return Err(Error::Codec("failed to decode audio packet", e));
+            }
+        }
+        self.decode(out_time_base, stats, &mut packets)?;
+        Ok(packets)
+    }
+
+    // send EOF through decoder, resampler and encoder, returns the remaining packets
+    pub fn flush(&mut self, out_time_base: Rational, stats: &Stats) -> Result<Vec<Packet>> {
+        let mut packets = Vec::new();
+        // a silent track has nothing buffered before the encoder
+        if let Some(dec_ctx) = &mut self.dec_ctx {
+            dec_ctx
+                .send_eof()
+                .map_err(|e| Error::Codec("failed to flush audio decoder", e))?;
+            self.decode(out_time_base, stats, &mut packets)?;
+            let mut buffersrc_ctx = graph_filter(&mut self.filter_graph, "in")?;
+            buffersrc_ctx
+                .source()
+                .flush()
+                .map_err(|e| Error::Filter("failed to flush the audio filter graph", e))?;
+            while encode_frame(
+                &mut self.filter_graph,
+                &mut self.filter_frame,
+                &mut self.enc_ctx,
+                None,
+                (self.stream_index, out_time_base),
+                stats,
+                &mut packets,
+            )? {}
+        }
+        self.enc_ctx
+            .send_eof()
+            .map_err(|e| Error::Codec("failed to flush audio encoder", e))?;
+        receive_packets(
+            &mut self.enc_ctx,
+            (self.stream_index, out_time_base),
+            stats,
+            &mut packets,
+        )?;
+        Ok(packets)
+    }
+
+    // push every frame the decoder has ready through the graph and the encoder
+    fn decode(
+        &mut self,
+        out_time_base: Rational,
+        stats: &Stats,
+        packets: &mut Vec<Packet>,
+    ) -> Result<()> {
+        let dec_ctx = match &mut self.dec_ctx {
+            Some(dec_ctx) => dec_ctx,
+            None => return Ok(()),
+        };
+        loop {
+            match dec_ctx.receive_frame(&mut self.de_frame) {
+                Ok(()) => {}
+                Err(e) if is_drained(&e) => return Ok(()),
+                Err(e) => {
+                    Stats::incr(&stats.decode_errors);
+                    return Err(Error::Codec("failed to decode audio frame", e));
+                }
+            }
+            let timestamp = self.de_frame.timestamp();
+            self.de_frame.set_pts(timestamp);
+            let mut buffersrc_ctx = graph_filter(&mut self.filter_graph, "in")?;
+            buffersrc_ctx
+                .source()
+                .add(&self.de_frame)
+                .map_err(|e| Error::Filter("failed to feed the audio filter graph", e))?;
+            while encode_frame(
+                &mut self.filter_graph,
+                &mut self.filter_frame,
+                &mut self.enc_ctx,
+                None,
+                (self.stream_index, out_time_base),
+                stats,
+                packets,
+            )? {}
+        }
+    }
+
+    // encode silence up to `until_us` of session time, the same clock as the
+    // video so that both go through the output clock together
+    pub fn fill_silence(
+        &mut self,
+        until_us: i64,
+        out_time_base: Rational,
+        stats: &Stats,
+    ) -> Result<Vec<Packet>> {
+        let mut packets = Vec::new();
+        let enc_time_base = Rational::from(unsafe { (*self.enc_ctx.as_ptr()).time_base });
+        let until = until_us.rescale(US, enc_time_base);
+        // the track starts with the video, which may be far from zero
+        let next_pts = self.next_pts.get_or_insert(until);
+        // the video clock went back further than a second
+        if until + 1_i64.rescale(Rational(1, 1), enc_time_base) < *next_pts {
+            *next_pts = until;
+        }
+        while *next_pts < until {
+            let filled = encode_frame(
+                &mut self.filter_graph,
+                &mut self.filter_frame,
+                &mut self.enc_ctx,
+                Some(&mut *next_pts),
+                (self.stream_index, out_time_base),
+                stats,
+                &mut packets,
+            )?;
+            if !filled {
+                break;
+            }
+        }
+        Ok(packets)
+    }
+}
+
+fn open_encoder(
+    conf: &AudioConf,
+    in_rate: u32,
+    in_layout: ChannelLayout,
+    global_header: bool,
+) -> Result<(Codec, encoder::Audio)> {
+    let codec = encoder::find_by_name(&conf.codec)
+        .ok_or_else(|| Error::Config(format!("unknown audio encoder {}", conf.codec)))?;
+    let audio_codec = codec
+        .audio()
+        .map_err(|_| Error::Config(format!("{} is not an audio encoder", conf.codec)))?;
+    let rate = conf.output_rate(&audio_codec, in_rate);
+    let layout = conf.output_layout(in_layout);
+    let sample_fmt = audio_codec
+        .formats()
+        .and_then(|mut formats| formats.next())
+        .unwrap_or(Sample::F32(format::sample::Type::Planar));
+    let mut codec_ctx = Context::new()
+        .encoder()
+        .audio()
+        .map_err(|e| Error::Codec("failed to alloc audio encoder", e))?;
+    codec_ctx.set_rate(rate as i32);
+    codec_ctx.set_channel_layout(layout);
+    codec_ctx.set_channels(layout.channels());
+    codec_ctx.set_format(sample_fmt);
+    codec_ctx.set_bit_rate(conf.bitrate * 1000);
+    codec_ctx.set_time_base(Rational::new(1, rate as i32));
+    if global_header {
+        codec_ctx.set_flags(codec::Flags::GLOBAL_HEADER);
+    }
+    let enc_ctx = codec_ctx
+        .open_as(codec)
+        .map_err(|e| Error::Codec("failed to open audio encoder", e))?;
+    Ok((codec, enc_ctx))
+}
+
+// source "in" -> anull -> sink "out" in the encoder's sample format, rate and layout
+fn open_graph(source: &str, args: &str, codec: &Codec, enc_ctx: &encoder::Audio) -> Result<Graph> {
+    let mut filter_graph = Graph::new();
+    let source = filter::find(source).ok_or(Error::Filter(
+        "audio source filter not found",
+        ffmpeg_next::Error::FilterNotFound,
+    ))?;
+    let abuffersink = filter::find("abuffersink").ok_or(Error::Filter(
+        "abuffersink filter not found",
+        ffmpeg_next::Error::FilterNotFound,
+    ))?;
+    filter_graph
+        .add(&source, "in", args)
+        .map_err(|e| Error::Filter("failed to create audio source", e))?;
+    let mut sink_ctx = filter_graph
+        .add(&abuffersink, "out", "")
+        .map_err(|e| Error::Filter("failed to create audio buffer sink", e))?;
+    sink_ctx.set_sample_format(enc_ctx.format());
+    sink_ctx.set_sample_rate(enc_ctx.rate());
+    sink_ctx.set_channel_layout(enc_ctx.channel_layout());
+    filter_graph
+        .output("in", 0)
+        .and_then(|parser| parser.input("out", 0))
+        .and_then(|parser| parser.parse("anull"))
+        .map_err(|e| Error::Filter("failed to parse audio filter", e))?;
+    filter_graph
+        .validate()
+        .map_err(|e| Error::Filter("failed to connect audio filters", e))?;
+    // most encoders, AAC included, take a fixed number of samples per frame
+    if !codec
+        .capabilities()
+        .contains(codec::Capabilities::VARIABLE_FRAME_SIZE)
+    {
+        let mut sink_ctx = graph_filter(&mut filter_graph, "out")?;
+        sink_ctx.sink().set_frame_size(enc_ctx.frame_size());
+    }
+    Ok(filter_graph)
+}
+
+// pull one frame from the sink and encode it, false if the sink is empty.
+// Silence is stamped from `clock` (encoder time base) instead of the sink timestamps.
+fn encode_frame(
+    filter_graph: &mut Graph,
+    filter_frame: &mut frame::Audio,
+    enc_ctx: &mut encoder::Audio,
+    clock: Option<&mut i64>,
+    (stream_index, out_time_base): (usize, Rational),
+    stats: &Stats,
+    packets: &mut Vec<Packet>,
+) -> Result<bool> {
+    let enc_time_base = Rational::from(unsafe { (*enc_ctx.as_ptr()).time_base });
+    let mut buffersink_ctx = graph_filter(filter_graph, "out")?;
+    let mut buffersink_ctx = buffersink_ctx.sink();
+    match buffersink_ctx.frame(filter_frame) {
+        Ok(()) => {}
+        Err(e) if is_drained(&e) => return Ok(false),
+        Err(e) => {
+            return Err(Error::Filter(
+                "failed to pull from the audio filter graph",
+                e,
+            ))
+        }
+    }
+    match clock {
+        Some(clock) => {
+            filter_frame.set_pts(Some(*clock));
+            let rate = Rational(1, enc_ctx.rate() as i32);
+            *clock += (filter_frame.samples() as i64).rescale(rate, enc_time_base);
+        }
+        None => {
+            let sink_time_base = buffersink_ctx.time_base();
+            if let Some(pts) = filter_frame.pts() {
+                filter_frame.set_pts(Some(pts.rescale(sink_time_base, enc_time_base)));
+            }
+        }
+    }
+    if let Err(e) = enc_ctx.send_frame(filter_frame) {
+        Stats::incr(&stats.encode_errors);
+        return Err(Error::Codec("failed to send frame to audio encoder", e));
+    }
+    receive_packets(enc_ctx, (stream_index, out_time_base), stats, packets)?;
+    Ok(true)
+}
+
+// collect every packet the encoder has ready, in the output time base
+fn receive_packets(
+    enc_ctx: &mut encoder::Audio,
+    (stream_index, out_time_base): (usize, Rational),
+    stats: &Stats,
+    packets: &mut Vec<Packet>,
+) -> Result<()> {
+    let enc_time_base = Rational::from(unsafe { (*enc_ctx.as_ptr()).time_base });
+    let mut en_pkt = Packet::empty();
+    loop {
+        match enc_ctx.receive_packet(&mut en_pkt) {
+            Ok(()) => {}
+            Err(e) if is_drained(&e) => return Ok(()),
+            Err(e) => {
+                Stats::incr(&stats.encode_errors);
+                return Err(Error::Codec("failed to receive audio packet", e));
+            }
+        }
+        en_pkt.set_stream(stream_index);
+        en_pkt.rescale_ts(enc_time_base, out_time_base);
+        packets.push(en_pkt);
+        en_pkt = Packet::empty();
+    }
+}
@@ -0,0 +1,130 @@
+use ffmpeg_next::{codec, format};
+use ffmpeg_sys_next::{av_codec_get_id, av_codec_get_tag2};
+use serde::Deserialize;
+use std::path::Path;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Container {
+    // from the output URL scheme or file extension, flv if neither tells
+    #[default]
+    Auto,
+    Flv,
+    Mpegts,
+    Mp4,
+    // fragmented mp4, playable while it is being written
+    Fmp4,
+    Matroska,
+    // playlist and segments, see HlsConf
+    Hls,
+}
+
+impl Container {
+    pub fn resolve(self, url: &str) -> Container {
+        match self {
+            Container::Auto => guess(url),
+            container => container,
+        }
+    }
+
+    // muxer name for avformat_alloc_output_context2
+    pub fn name(self) -> &'static str {
+        match self {
+            Container::Auto | Container::Flv => "flv",
+            Container::Mpegts => "mpegts",
+            Container::Mp4 | Container::Fmp4 => "mp4",
+            Container::Matroska => "matroska",
+            Container::Hls => "hls",
+        }
+    }
+
+    // file extension for recordings
+    pub fn extension(self) -> &'static str {
+        match self {
+            Container::Auto | Container::Flv => "flv",
+            Container::Mpegts => "ts",
+            Container::Mp4 | Container::Fmp4 => "mp4",
+            Container::Matroska => "mkv",
+            Container::Hls => "m3u8",
+        }
+    }
+
+    // muxer options passed to avformat_write_header
+    pub fn mux_options(self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            // moov in front once the trailer is written
+            Container::Mp4 => &[("movflags", "+faststart")],
+            Container::Fmp4 => &[("movflags", "+frag_keyframe+empty_moov+default_base_moof")],
+            _ => &[],
+        }
+    }
+}
+
+fn guess(url: &str) -> Container {
+    let url = url.to_ascii_lowercase();
+    if let Some((scheme, _)) = url.split_once("://") {
+        match scheme {
+            "rtmp" | "rtmps" => return Container::Flv,
+            "srt" | "udp" => return Container::Mpegts,
+            _ => {}
+        }
+    }
+    let path = url.split(['?', '#']).next().unwrap_or_default();
+    let extension = Path::new(path).extension().and_then(|ext| ext.to_str());
+    match extension {
+        Some("ts") | Some("m2ts") => Container::Mpegts,
+        Some("mp4") | Some("m4v") | Some("mov") => Container::Mp4,
+        Some("mkv") | Some("mka") => Container::Matroska,
+        Some("m3u8") => Container::Hls,
+        _ => Container::Flv,
+    }
+}
+
+// tag for a copied stream: keep the input tag if the container maps it to the same
+// codec or has no tag for the codec at all, otherwise 0 lets the muxer choose
+pub fn codec_tag(out_format: &format::Output, codec_id: codec::Id, tag: u32) -> u32 {
+    unsafe {
+        let tags = (*out_format.as_ptr()).codec_tag;
+        let mut own_tag = 0;
+        if tag == 0
+            || tags.is_null()
+            || av_codec_get_id(tags, tag) == codec_id.into()
+            || av_codec_get_tag2(tags, codec_id.into(), &mut own_tag) == 0
+        {
+            tag
+        } else {
+            0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn guess_from_scheme() {
+        assert_eq!(guess("rtmp://live.example.com/app/key"), Container::Flv);
+        assert_eq!(guess("RTMPS://live.example.com/app/key.ts"), Container::Flv);
+        assert_eq!(guess("srt://10.0.0.1:9000"), Container::Mpegts);
+        assert_eq!(guess("udp://239.0.0.1:1234"), Container::Mpegts);
+    }
+
+    #[test]
+    fn guess_from_extension() {
+        assert_eq!(guess("/tmp/out.ts"), Container::Mpegts);
+        assert_eq!(guess("/tmp/out.MP4"), Container::Mp4);
+        assert_eq!(guess("/tmp/out.mkv"), Container::Matroska);
+        assert_eq!(
+            guess("http://cdn.example.com/live.m3u8?token=a.mp4"),
+            Container::Hls
+        );
+        assert_eq!(guess("/tmp/out"), Container::Flv);
+    }
+
+    #[test]
+    fn resolve_keeps_an_explicit_container() {
+        assert_eq!(Container::Mpegts.resolve("/tmp/out.mp4"), Container::Mpegts);
+        assert_eq!(Container::Auto.resolve("/tmp/out.mp4"), Container::Mp4);
+    }
+}
@@ -0,0 +1,407 @@
+use ffmpeg_next::{codec, dictionary::Owned, encoder, format::Pixel, Codec, Rational};
+use ffmpeg_sys_next::{av_get_pix_fmt, AVPixelFormat};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::ffi::CString;
+
+use super::audio::AudioConf;
+use crate::error::{Error, Result};
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RateControl {
+    // average bitrate
+    Abr,
+    // constant bitrate, max rate and buffer size follow `bitrate`
+    Cbr,
+    // constant quality, `bitrate` is ignored
+    Crf,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ScaleMode {
+    // exactly width x height, aspect ratio is not kept
+    Stretch,
+    // largest size inside width x height keeping the aspect ratio
+    #[default]
+    Fit,
+    // fit, then pad to width x height with black borders
+    Pad,
+    // fill width x height keeping the aspect ratio, then crop the overflow
+    Crop,
+}
+
+// output frame rate, a number such as 25 or 29.97 or a fraction such as
+// "30000/1001"
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "FpsValue")]
+pub struct Fps(pub Rational);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum FpsValue {
+    Number(f64),
+    Text(String),
+}
+
+impl TryFrom<FpsValue> for Fps {
+    type Error = String;
+
+    fn try_from(value: FpsValue) -> std::result::Result<Self, Self::Error> {
+        let rate = match value {
+            FpsValue::Number(fps) => Rational::from(fps),
+            FpsValue::Text(text) => match text.split_once('/') {
+                Some((num, den)) => match (num.trim().parse(), den.trim().parse()) {
+                    (Ok(num), Ok(den)) => Rational::new(num, den),
+                    _ => return Err(format!("invalid fps {:?}", text)),
+                },
+                None => match text.trim().parse::<f64>() {
+                    Ok(fps) => Rational::from(fps),
+                    Err(_) => return Err(format!("invalid fps {:?}", text)),
+                },
+            },
+        };
+        // a zero rate would make the encoder time base 1/0
+        match rate.numerator() > 0 && rate.denominator() > 0 {
+            true => Ok(Fps(rate)),
+            false => Err(format!("fps must be positive, got {}", rate)),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct EncoderConf {
+    // encoder name such as "libx264", defaults to an encoder for the input codec
+    pub codec: Option<String>,
+    #[serde(default = "default_rate_control")]
+    pub rate_control: RateControl,
+    // kbit/s
+    #[serde(default = "default_bitrate")]
+    pub bitrate: usize,
+    #[serde(default = "default_crf")]
+    pub crf: u32,
+    #[serde(default = "default_gop")]
+    pub gop: u32,
+    #[serde(default)]
+    pub b_frames: usize,
+    pub preset: Option<String>,
+    pub tune: Option<String>,
+    pub profile: Option<String>,
+    // encoder private options, e.g. {"x264-params": "keyint=50"}
+    #[serde(default)]
+    pub options: HashMap<String, String>,
+    // output size, a single dimension keeps the input aspect ratio
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    #[serde(default)]
+    pub scale_mode: ScaleMode,
+    pub fps: Option<Fps>,
+    pub pix_fmt: Option<String>,
+    #[serde(default)]
+    pub audio: AudioConf,
+}
+
+fn default_rate_control() -> RateControl {
+    RateControl::Abr
+}
+
+fn default_bitrate() -> usize {
+    2564
+}
+
+fn default_crf() -> u32 {
+    23
+}
+
+fn default_gop() -> u32 {
+    50
+}
+
+impl Default for EncoderConf {
+    fn default() -> Self {
+        EncoderConf {
+            codec: None,
+            rate_control: default_rate_control(),
+            bitrate: default_bitrate(),
+            crf: default_crf(),
+            gop: default_gop(),
+            b_frames: 0,
+            preset: None,
+            tune: None,
+            profile: None,
+            options: HashMap::new(),
+            width: None,
+            height: None,
+            scale_mode: ScaleMode::default(),
+            fps: None,
+            pix_fmt: None,
+            audio: AudioConf::default(),
+        }
+    }
+}
+
+impl EncoderConf {
+    pub fn find(&self, dec_id: codec::Id) -> Result<Codec> {
+        match &self.codec {
+            Some(name) => encoder::find_by_name(name)
+                .ok_or_else(|| Error::Config(format!("unknown encoder {}", name))),
+            None => encoder::find(dec_id).ok_or(Error::Codec(
+                "failed to find encoder",
+                ffmpeg_next::Error::EncoderNotFound,
+            )),
+        }
+    }
+
+    // rate control and GOP settings on the encoder context
+    pub fn configure(&self, codec_ctx: &mut encoder::video::Video) {
+        codec_ctx.set_gop(self.gop);
+        codec_ctx.set_max_b_frames(self.b_frames);
+        let bitrate = self.bitrate * 1000;
+        match self.rate_control {
+            RateControl::Abr => codec_ctx.set_bit_rate(bitrate),
+            RateControl::Cbr => {
+                codec_ctx.set_bit_rate(bitrate);
+                codec_ctx.set_max_bit_rate(bitrate);
+                unsafe {
+                    (*codec_ctx.as_mut_ptr()).rc_min_rate = bitrate as i64;
+                    (*codec_ctx.as_mut_ptr()).rc_buffer_size = bitrate as i32;
+                }
+            }
+            RateControl::Crf => {}
+        }
+    }
+
+    // largest GOP up to `gop` that puts a keyframe on every segment boundary
+    pub fn segment_gop(&self, frame_rate: Rational, segment_times: &[u32]) -> u32 {
+        let frames = segment_times
+            .iter()
+            .map(|time| (f64::from(frame_rate) * *time as f64).round() as u32)
+            .fold(0, gcd);
+        if frames == 0 {
+            return self.gop;
+        }
+        (1..=self.gop.min(frames))
+            .rev()
+            .find(|gop| frames % gop == 0)
+            .unwrap_or(1)
+    }
+
+    // scaling, frame rate or pixel format conversion needs a filter graph
+    pub fn needs_filter(&self) -> bool {
+        self.width.is_some()
+            || self.height.is_some()
+            || self.fps.is_some()
+            || self.pix_fmt.is_some()
+    }
+
+    pub fn output_format(&self) -> Result<Pixel> {
+        let name = match &self.pix_fmt {
+            Some(name) => name,
+            None => return Ok(Pixel::YUV420P),
+        };
+        let format = CString::new(name.as_str())
+            .map(|name| unsafe { av_get_pix_fmt(name.as_ptr()) })
+            .unwrap_or(AVPixelFormat::AV_PIX_FMT_NONE);
+        match format {
+            AVPixelFormat::AV_PIX_FMT_NONE => {
+                Err(Error::Config(format!("unknown pixel format {}", name)))
+            }
+            format => Ok(Pixel::from(format)),
+        }
+    }
+
+    pub fn output_frame_rate(&self, in_frame_rate: Rational) -> Rational {
+        match self.fps {
+            Some(Fps(fps)) => fps,
+            None => in_frame_rate,
+        }
+    }
+
+    // (scaled size, final size) for an input size
+    fn scaled_size(&self, in_w: u32, in_h: u32) -> ((u32, u32), (u32, u32)) {
+        // even dimensions for chroma subsampled formats
+        let even = |v: f64| ((v / 2.0).round() as u32 * 2).max(2);
+        let (in_w, in_h) = (in_w as f64, in_h as f64);
+        match (self.width, self.height) {
+            (None, None) => ((in_w as u32, in_h as u32), (in_w as u32, in_h as u32)),
+            (Some(w), None) => {
+                let size = (even(w as f64), even(in_h * w as f64 / in_w));
+                (size, size)
+            }
+            (None, Some(h)) => {
+                let size = (even(in_w * h as f64 / in_h), even(h as f64));
+                (size, size)
+            }
+            (Some(w), Some(h)) => {
+                let target = (even(w as f64), even(h as f64));
+                let fit = (w as f64 / in_w).min(h as f64 / in_h);
+                let fill = (w as f64 / in_w).max(h as f64 / in_h);
+                match self.scale_mode {
+                    ScaleMode::Stretch => (target, target),
+                    ScaleMode::Fit => {
+                        let size = (even(in_w * fit), even(in_h * fit));
+                        (size, size)
+                    }
+                    ScaleMode::Pad => ((even(in_w * fit), even(in_h * fit)), target),
+                    ScaleMode::Crop => ((even(in_w * fill), even(in_h * fill)), target),
+                }
+            }
+        }
+    }
+
+    pub fn output_size(&self, in_w: u32, in_h: u32) -> (u32, u32) {
+        self.scaled_size(in_w, in_h).1
+    }
+
+    // filters appended after the OSD stage, as (filter name, args)
+    pub fn post_filters(&self, in_w: u32, in_h: u32) -> Vec<(&'static str, String)> {
+        let mut filters = Vec::new();
+        if self.width.is_some() || self.height.is_some() {
+            let ((sw, sh), (w, h)) = self.scaled_size(in_w, in_h);
+            filters.push(("scale", format!("w={}:h={}", sw, sh)));
+            if (sw, sh) != (w, h) {
+                match self.scale_mode {
+                    ScaleMode::Crop => filters.push(("crop", format!("w={}:h={}", w, h))),
+                    _ => filters.push(("pad", format!("w={}:h={}:x=(ow-iw)/2:y=(oh-ih)/2", w, h))),
+                }
+            }
+            filters.push(("setsar", "sar=1".to_string()));
+        }
+        if let Some(Fps(fps)) = self.fps {
+            filters.push((
+                "fps",
+                format!("fps={}/{}", fps.numerator(), fps.denominator()),
+            ));
+        }
+        if let Some(pix_fmt) = &self.pix_fmt {
+            filters.push(("format", format!("pix_fmts={}", pix_fmt)));
+        }
+        filters
+    }
+
+    // private options passed to avcodec_open2
+    pub fn dict(&self) -> Owned<'static> {
+        let mut dict = Owned::new();
+        for (key, value) in &self.options {
+            dict.set(key, value);
+        }
+        let named = [
+            ("preset", &self.preset),
+            ("tune", &self.tune),
+            ("profile", &self.profile),
+        ];
+        for (key, value) in named {
+            if let Some(value) = value {
+                dict.set(key, value);
+            }
+        }
+        if self.rate_control == RateControl::Crf {
+            dict.set("crf", &self.crf.to_string());
+        }
+        dict
+    }
+
+    // like dict, with keyframes only on the GOP when the output is segmented
+    pub fn segment_dict(&self, segmented: bool) -> Owned<'static> {
+        let mut dict = self.dict();
+        if segmented && !self.options.contains_key("sc_threshold") {
+            // libx264 would add keyframes on scene cuts, off the segment grid
+            dict.set("sc_threshold", "0");
+        }
+        dict
+    }
+}
+
+fn gcd(a: u32, b: u32) -> u32 {
+    match b {
+        0 => a,
+        b => gcd(b, a % b),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scaled(width: Option<u32>, height: Option<u32>, scale_mode: ScaleMode) -> EncoderConf {
+        EncoderConf {
+            width,
+            height,
+            scale_mode,
+            ..EncoderConf::default()
+        }
+    }
+
+    #[test]
+    fn scaled_size_keeps_aspect_with_one_dimension() {
+        let conf = scaled(Some(640), None, ScaleMode::Fit);
+        assert_eq!(conf.scaled_size(1920, 1080), ((640, 360), (640, 360)));
+        let conf = scaled(None, Some(480), ScaleMode::Fit);
+        assert_eq!(conf.scaled_size(1920, 1080), ((854, 480), (854, 480)));
+    }
+
+    #[test]
+    fn scaled_size_modes() {
+        let size = |mode| scaled(Some(640), Some(640), mode).scaled_size(1920, 1080);
+        assert_eq!(size(ScaleMode::Stretch), ((640, 640), (640, 640)));
+        assert_eq!(size(ScaleMode::Fit), ((640, 360), (640, 360)));
+        assert_eq!(size(ScaleMode::Pad), ((640, 360), (640, 640)));
+        assert_eq!(size(ScaleMode::Crop), ((1138, 640), (640, 640)));
+    }
+
+    #[test]
+    fn scaled_size_is_even() {
+        let conf = scaled(Some(101), None, ScaleMode::Fit);
+        assert_eq!(conf.scaled_size(1920, 1080), ((102, 56), (102, 56)));
+        assert_eq!(
+            scaled(None, None, ScaleMode::Fit).scaled_size(7, 5),
+            ((7, 5), (7, 5))
+        );
+    }
+
+    #[test]
+    fn segment_gop_divides_every_segment() {
+        let conf = EncoderConf::default();
+        let pal = Rational::new(25, 1);
+        assert_eq!(conf.segment_gop(pal, &[]), 50);
+        assert_eq!(conf.segment_gop(pal, &[4]), 50);
+        assert_eq!(conf.segment_gop(pal, &[3]), 25);
+        assert_eq!(conf.segment_gop(pal, &[4, 6]), 50);
+        assert_eq!(conf.segment_gop(pal, &[4, 3]), 25);
+        assert_eq!(conf.segment_gop(Rational::new(24, 1), &[4]), 48);
+        assert_eq!(conf.segment_gop(Rational::new(30000, 1001), &[4]), 40);
+    }
+
+    #[test]
+    fn fps_accepts_numbers_and_fractions() {
+        let fps = |value| Fps::try_from(value).map(|Fps(fps)| fps);
+        assert_eq!(fps(FpsValue::Number(25.0)), Ok(Rational::new(25, 1)));
+        assert_eq!(fps(FpsValue::Number(29.97)), Ok(Rational::new(2997, 100)));
+        let ntsc = FpsValue::Text("30000/1001".to_string());
+        assert_eq!(fps(ntsc), Ok(Rational::new(30000, 1001)));
+        assert_eq!(
+            fps(FpsValue::Text("50".to_string())),
+            Ok(Rational::new(50, 1))
+        );
+    }
+
+    #[test]
+    fn fps_rejects_zero_and_garbage() {
+        assert!(Fps::try_from(FpsValue::Number(0.0)).is_err());
+        assert!(Fps::try_from(FpsValue::Number(-25.0)).is_err());
+        assert!(Fps::try_from(FpsValue::Text("30000/0".to_string())).is_err());
+        assert!(Fps::try_from(FpsValue::Text("fast".to_string())).is_err());
+    }
+
+    #[test]
+    fn unknown_names_are_config_errors() {
+        let conf = EncoderConf {
+            codec: Some("no_such_encoder".to_string()),
+            pix_fmt: Some("no_such_format".to_string()),
+            ..EncoderConf::default()
+        };
+        assert!(matches!(conf.output_format(), Err(Error::Config(_))));
+        assert!(matches!(conf.find(codec::Id::H264), Err(Error::Config(_))));
+    }
+}
@@ -7,24 +7,57 @@ use ffmpeg_next::{
     format::{
         self,
         context::{input, output, Input, Output},
+        stream::{Stream, StreamMut},
     },
     media::Type,
+    util::error::ENOMEM,
     Rational,
 };
-use ffmpeg_sys_next::{av_guess_frame_rate, avcodec_parameters_copy};
-use std::path::Path;
+use ffmpeg_sys_next::{
+    av_guess_frame_rate, avcodec_parameters_copy, avcodec_parameters_from_context,
+    avformat_alloc_context, avformat_alloc_output_context2, avformat_close_input,
+    avformat_find_stream_info, avformat_open_input, avio_open2, AVFMT_NOFILE, AVIO_FLAG_WRITE,
+};
+use serde::Serialize;
+use std::ffi::CString;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::ptr;
+use std::sync::Arc;
+
+use super::{
+    audio::AudioCtx,
+    container::{self, Container},
+    encoder::EncoderConf,
+    interrupt::Interrupt,
+    output::{OutputConf, OutputCtx},
+};
+use crate::error::{Error, Result};
 
 pub struct FmtCtx {
-    pub in_fmt_ctx: Input,   // AVFormatContext
-    pub out_fmt_ctx: Output, // AVFormatContext
+    pub in_fmt_ctx: Input, // AVFormatContext
+    pub outputs: Vec<OutputCtx>,
+    // output stream of the video, the same in every output
+    pub video_index: usize,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct StreamInfo {
+    pub video_codec: String,
+    pub width: u32,
+    pub height: u32,
+    pub fps: f64,
+    pub audio_codec: Option<String>,
 }
 
 pub struct StreamCtx {
     pub dec_ctx: decoder::Video, //AVCodecContext
-    pub enc_ctx: encoder::Video,
+    // None when the video is remuxed
+    pub enc_ctx: Option<encoder::Video>,
     pub de_frame: Video, //AVFrame
-    pub stream_idx: (u32, u32),
+    // (video, audio), audio is None for video only inputs
+    pub stream_idx: (u32, Option<u32>),
+    pub audio: Vec<AudioCtx>,
     pub fmt_ctx: FmtCtx,
 }
 
@@ -32,45 +65,64 @@ impl StreamCtx {
     pub fn init(
         in_path: &Path,
         in_config: Option<Owned>,
-        out_path: &Path,
-        fmt: &str,
-        out_config: Option<Owned>,
-    ) -> Self {
-        let (in_fmt_ctx, dec_ctx, stream_idx) = StreamCtx::input_open(in_path, in_config);
-        let (out_fmt_ctx, enc_ctx) =
-            StreamCtx::out_open(out_path, fmt, out_config, &in_fmt_ctx, &dec_ctx);
-        StreamCtx {
+        outputs: &[OutputConf],
+        enc_conf: &EncoderConf,
+        encode: bool,
+        interrupt: &Interrupt,
+    ) -> Result<Self> {
+        let (in_fmt_ctx, dec_ctx, stream_idx) =
+            StreamCtx::input_open(in_path, in_config, interrupt)?;
+        let (outputs, video_index, enc_ctx, audio) =
+            StreamCtx::out_open(outputs, &in_fmt_ctx, &dec_ctx, enc_conf, encode, interrupt)?;
+        Ok(StreamCtx {
             dec_ctx,
             enc_ctx,
             de_frame: Video::new(Pixel::YUV420P, 1280, 800),
             stream_idx,
+            audio,
             fmt_ctx: FmtCtx {
                 in_fmt_ctx,
-                out_fmt_ctx,
+                outputs,
+                video_index,
             },
+        })
+    }
+
+    pub fn info(&self) -> StreamInfo {
+        let audio_codec = self
+            .fmt_ctx
+            .in_fmt_ctx
+            .streams()
+            .find(|stream| stream.parameters().medium() == Type::Audio)
+            .map(|stream| stream.parameters().id().name().to_string());
+        StreamInfo {
+            video_codec: self.dec_ctx.id().name().to_string(),
+            width: self.dec_ctx.width(),
+            height: self.dec_ctx.height(),
+            fps: self.dec_ctx.frame_rate().map(f64::from).unwrap_or(0.0),
+            audio_codec,
         }
     }
 
     pub fn input_open(
         file_path: &Path,
         options: Option<Owned>,
-    ) -> (Input, decoder::Video, (u32, u32)) {
+        interrupt: &Interrupt,
+    ) -> Result<(Input, decoder::Video, (u32, Option<u32>))> {
         let mut dec_ctx = None;
-        let mut stream_idx = (0, 0);
+        let mut stream_idx = (0, None);
 
-        let in_fmt_ctx = match options {
-            Some(op) => {
-                format::input_with_dictionary(&file_path, op).expect("Failed to open input file")
-            }
-            None => format::input(&file_path).expect("Failed to open input file"),
-        };
+        interrupt.arm();
+        let in_fmt_ctx = open_input(file_path, options, interrupt)
+            .map_err(|e| Error::Open(file_path.display().to_string(), e))?;
 
-        for i in 0..in_fmt_ctx.nb_streams() {
-            let stream = in_fmt_ctx.stream(i as usize).unwrap();
+        for stream in in_fmt_ctx.streams() {
+            let i = stream.index() as u32;
             match stream.parameters().medium() {
                 Type::Video => {
                     let parameters = stream.parameters();
-                    let codec_ctx = Context::from_parameters(parameters).unwrap();
+                    let codec_ctx = Context::from_parameters(parameters)
+                        .map_err(|e| Error::Codec("failed to copy decoder parameters", e))?;
                     let mut codec_ctx = codec_ctx.decoder();
                     unsafe {
                         (*codec_ctx.as_mut_ptr()).framerate = av_guess_frame_rate(
@@ -79,84 +131,339 @@ impl StreamCtx {
                             ptr::null_mut(),
                         );
                     }
-                    dec_ctx = Some(codec_ctx.video().unwrap());
+                    dec_ctx = Some(
+                        codec_ctx
+                            .video()
+                            .map_err(|e| Error::Codec("failed to open decoder", e))?,
+                    );
                     stream_idx.0 = i;
                 }
                 Type::Audio => {
-                    stream_idx.1 = i;
+                    stream_idx.1 = Some(i);
                 }
                 _ => {}
             }
         }
         // print input info
         input::dump(&in_fmt_ctx, 0, file_path.to_str());
-        (in_fmt_ctx, dec_ctx.unwrap(), stream_idx)
+        let dec_ctx = dec_ctx.ok_or_else(|| Error::Probe("no video stream found".to_string()))?;
+        if stream_idx.1.is_none() {
+            log::info!("no audio stream, video keeps its own timestamps");
+        }
+        Ok((in_fmt_ctx, dec_ctx, stream_idx))
     }
 
+    // set up every output, the streams are added to the first one and copied to
+    // the others so that one encoder can feed them all. Without `encode` the
+    // video stream takes the input codec parameters and no encoder is opened.
+    // Nothing is opened yet, see OutputCtx::start. Every output times out on
+    // its own interrupt, so that it can reconnect next to the session.
     pub fn out_open(
-        file_path: &Path,
-        fmt: &str,
-        options: Option<Owned>,
+        outputs: &[OutputConf],
         in_fmt_ctx: &Input,
         dec_ctx: &decoder::Video,
-    ) -> (Output, encoder::Video) {
+        enc_conf: &EncoderConf,
+        encode: bool,
+        interrupt: &Interrupt,
+    ) -> Result<(Vec<OutputCtx>, usize, Option<encoder::Video>, Vec<AudioCtx>)> {
         let mut enc_ctx = None;
+        let mut video_index = None;
+        let mut audio = Vec::new();
 
-        let mut out_fmt_ctx = match options {
-            Some(op) => {
-                format::output_as_with(&file_path, fmt, op).expect("Failed to open output URL")
-            }
-            None => format::output_as(&file_path, fmt).expect("Failed to open output URL"),
+        let mut out_fmt_ctxs = Vec::new();
+        let mut opened = Vec::new();
+        for conf in outputs {
+            let out_interrupt = Arc::new(Interrupt::new(interrupt.timeout_ms()));
+            let (out_fmt_ctx, path) = alloc_conf(conf, &out_interrupt)?;
+            out_fmt_ctxs.push(out_fmt_ctx);
+            opened.push((path, out_interrupt));
+        }
+        let out_formats: Vec<format::Output> =
+            out_fmt_ctxs.iter().map(|ctx| ctx.format()).collect();
+        // global headers if any container wants them, audio transcoded if any
+        // container can't carry the input codec
+        let global_header = out_formats
+            .iter()
+            .any(|fmt| fmt.flags().contains(format::Flags::GLOBAL_HEADER));
+        // segmenting outputs cut on keyframes
+        let segment_times: Vec<u32> = outputs.iter().filter_map(|c| c.segment_time()).collect();
+        let needs_transcode = |id| {
+            out_formats
+                .iter()
+                .any(|fmt| enc_conf.audio.needs_transcode(fmt, id))
         };
-
-        for i in 0..in_fmt_ctx.nb_streams() {
-            let in_stream = in_fmt_ctx.stream(i as usize).unwrap();
+        let out_fmt_ctx = out_fmt_ctxs
+            .first_mut()
+            .ok_or_else(|| Error::Probe("no output given".to_string()))?;
+        let out_format = out_fmt_ctx.format();
+        for in_stream in in_fmt_ctx.streams() {
             let mut out_stream = out_fmt_ctx
                 .add_stream(unsafe { Codec::wrap(ptr::null_mut()) })
-                .expect("Failed add output stream");
+                .map_err(|e| Error::Mux("failed to add output stream", e))?;
             let parameters = in_stream.parameters();
+            if parameters.medium() == Type::Video {
+                video_index = Some(out_stream.index());
+            }
             match parameters.medium() {
+                Type::Video if !encode => {
+                    copy_parameters(&in_stream, &mut out_stream, &out_format);
+                }
                 Type::Video => {
-                    let codec = codec::encoder::find(dec_ctx.id()).expect("Failed to find codec");
-                    let mut codec_ctx = Context::new().encoder().video().unwrap();
+                    let codec = enc_conf.find(dec_ctx.id())?;
+                    let mut codec_ctx = Context::new()
+                        .encoder()
+                        .video()
+                        .map_err(|e| Error::Codec("failed to alloc encoder", e))?;
                     // encode context configure
-                    codec_ctx.set_height(dec_ctx.height());
-                    codec_ctx.set_width(dec_ctx.width());
-                    codec_ctx.set_aspect_ratio(dec_ctx.aspect_ratio());
-                    codec_ctx.set_frame_rate(dec_ctx.frame_rate());
-                    codec_ctx.set_gop(50);
-                    codec_ctx.set_max_b_frames(0);
-                    codec_ctx.set_format(Pixel::YUV420P);
-                    codec_ctx.set_bit_rate(2564 * 1000);
+                    let (width, height) = enc_conf.output_size(dec_ctx.width(), dec_ctx.height());
+                    codec_ctx.set_height(height);
+                    codec_ctx.set_width(width);
+                    if enc_conf.width.is_some() || enc_conf.height.is_some() {
+                        // the scale stage ends with setsar=1
+                        codec_ctx.set_aspect_ratio(Rational::new(1, 1));
+                    } else {
+                        codec_ctx.set_aspect_ratio(dec_ctx.aspect_ratio());
+                    }
+                    let frame_rate = dec_ctx
+                        .frame_rate()
+                        .ok_or_else(|| Error::Probe("unknown video frame rate".to_string()))?;
+                    let frame_rate = enc_conf.output_frame_rate(frame_rate);
+                    codec_ctx.set_frame_rate(Some(frame_rate));
+                    codec_ctx.set_format(enc_conf.output_format()?);
+                    enc_conf.configure(&mut codec_ctx);
+                    let gop = enc_conf.segment_gop(frame_rate, &segment_times);
+                    if gop != enc_conf.gop {
+                        log::info!(
+                            "gop {} -> {} to start every segment on a keyframe",
+                            enc_conf.gop,
+                            gop
+                        );
+                    }
+                    codec_ctx.set_gop(gop);
                     codec_ctx.set_time_base(Rational::new(
-                        dec_ctx.frame_rate().unwrap().denominator(),
-                        dec_ctx.frame_rate().unwrap().numerator(),
+                        frame_rate.denominator(),
+                        frame_rate.numerator(),
                     ));
                     // // fix h264 setting
                     codec_ctx.set_qmin(10);
                     codec_ctx.set_qmax(51);
                     codec_ctx.set_me_range(16);
-                    let codec_ctx = codec_ctx.open_as(codec).unwrap();
-                    // set out stream
+                    if global_header {
+                        codec_ctx.set_flags(codec::Flags::GLOBAL_HEADER);
+                    }
+                    let codec_ctx = codec_ctx
+                        .open_as_with(codec, enc_conf.segment_dict(!segment_times.is_empty()))
+                        .map_err(|e| Error::Codec("failed to open encoder", e))?;
+                    // set out stream from the encoder, it may differ from the input codec
                     unsafe {
-                        out_stream.set_parameters(parameters);
+                        avcodec_parameters_from_context(
+                            (*out_stream.as_mut_ptr()).codecpar,
+                            codec_ctx.as_ptr(),
+                        );
                         (*out_stream.as_mut_ptr()).time_base = (*codec_ctx.as_ptr()).time_base;
                     }
                     enc_ctx = Some(codec_ctx);
                 }
-                Type::Audio => unsafe {
-                    avcodec_parameters_copy(
-                        (*out_stream.as_mut_ptr()).codecpar,
-                        (*in_stream.as_ptr()).codecpar,
-                    );
-                    out_stream.set_time_base(in_stream.time_base());
-                    (*(*out_stream.as_mut_ptr()).codecpar).codec_tag = 0;
-                },
+                Type::Audio if needs_transcode(parameters.id()) => {
+                    let audio_ctx = AudioCtx::open(&in_stream, &enc_conf.audio, global_header)?;
+                    audio_ctx.set_parameters(&mut out_stream);
+                    audio.push(audio_ctx);
+                }
+                Type::Audio => {
+                    copy_parameters(&in_stream, &mut out_stream, &out_format);
+                }
                 _ => {}
             }
         }
-        // print output info
-        output::dump(&out_fmt_ctx, 0, file_path.to_str());
-        (out_fmt_ctx, enc_ctx.unwrap())
+        let has_audio = in_fmt_ctx
+            .streams()
+            .any(|stream| stream.parameters().medium() == Type::Audio);
+        if !has_audio && enc_conf.audio.silence {
+            let mut out_stream = out_fmt_ctx
+                .add_stream(unsafe { Codec::wrap(ptr::null_mut()) })
+                .map_err(|e| Error::Mux("failed to add output stream", e))?;
+            let audio_ctx = AudioCtx::silence(out_stream.index(), &enc_conf.audio, global_header)?;
+            audio_ctx.set_parameters(&mut out_stream);
+            audio.push(audio_ctx);
+        }
+        for i in 1..out_fmt_ctxs.len() {
+            let (first, rest) = out_fmt_ctxs.split_at_mut(i);
+            copy_streams(&first[0], &mut rest[0])?;
+        }
+        let video_index =
+            video_index.ok_or_else(|| Error::Probe("no video stream found".to_string()))?;
+        let outputs = out_fmt_ctxs
+            .into_iter()
+            .zip(opened)
+            .zip(outputs)
+            .enumerate()
+            .map(|(i, ((out_fmt_ctx, (path, interrupt)), conf))| {
+                // print output info
+                output::dump(&out_fmt_ctx, i as i32, Some(conf.output.as_str()));
+                OutputCtx::new(i, conf.clone(), out_fmt_ctx, path, interrupt)
+            })
+            .collect();
+        Ok((outputs, video_index, enc_ctx, audio))
+    }
+
+    // set the output up again with the stream layout of a previous output context,
+    // the encoder keeps running so the codec parameters are copied over
+    pub fn out_alloc(
+        conf: &OutputConf,
+        pre_fmt_ctx: &Output,
+        interrupt: &Interrupt,
+    ) -> Result<(Output, PathBuf)> {
+        let (mut out_fmt_ctx, path) = alloc_conf(conf, interrupt)?;
+        copy_streams(pre_fmt_ctx, &mut out_fmt_ctx)?;
+        Ok((out_fmt_ctx, path))
+    }
+
+    // open the file or connection of an output set up by out_open or out_alloc
+    pub fn out_io_open(
+        out_fmt_ctx: &mut Output,
+        path: &Path,
+        conf: &OutputConf,
+        interrupt: &Interrupt,
+    ) -> Result<()> {
+        interrupt.arm();
+        open_io(out_fmt_ctx, path, conf.out_dict())
+            .map_err(|e| Error::Open(path.display().to_string(), e))
+    }
+}
+
+// set up the output of `conf` without opening it, a recording starts a new
+// file each time
+fn alloc_conf(conf: &OutputConf, interrupt: &Interrupt) -> Result<(Output, PathBuf)> {
+    let path = conf.path();
+    // hls and recordings write into a directory but don't create it
+    if conf.record.is_some() || conf.container() == Container::Hls {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)
+                .map_err(|e| Error::Probe(format!("failed to create {}: {}", dir.display(), e)))?;
+        }
+    }
+    let out_fmt_ctx = alloc_output(&path, conf.container().name(), interrupt)
+        .map_err(|e| Error::Open(path.display().to_string(), e))?;
+    Ok((out_fmt_ctx, path))
+}
+
+// stream copy from the input, the codec tag is fit to the output container
+fn copy_parameters(in_stream: &Stream, out_stream: &mut StreamMut, out_format: &format::Output) {
+    unsafe {
+        let codecpar = (*out_stream.as_mut_ptr()).codecpar;
+        avcodec_parameters_copy(codecpar, (*in_stream.as_ptr()).codecpar);
+        (*codecpar).codec_tag = container::codec_tag(
+            out_format,
+            in_stream.parameters().id(),
+            (*codecpar).codec_tag,
+        );
+    }
+    out_stream.set_time_base(in_stream.time_base());
+}
+
+// add the streams of `pre_fmt_ctx` to `out_fmt_ctx`, codec tags are fit to its container
+fn copy_streams(pre_fmt_ctx: &Output, out_fmt_ctx: &mut Output) -> Result<()> {
+    let out_format = out_fmt_ctx.format();
+    for pre_stream in pre_fmt_ctx.streams() {
+        let mut out_stream = out_fmt_ctx
+            .add_stream(unsafe { Codec::wrap(ptr::null_mut()) })
+            .map_err(|e| Error::Mux("failed to add output stream", e))?;
+        unsafe {
+            let codecpar = (*out_stream.as_mut_ptr()).codecpar;
+            avcodec_parameters_copy(codecpar, (*pre_stream.as_ptr()).codecpar);
+            (*out_stream.as_mut_ptr()).time_base = (*pre_stream.as_ptr()).time_base;
+            (*codecpar).codec_tag = container::codec_tag(
+                &out_format,
+                pre_stream.parameters().id(),
+                (*codecpar).codec_tag,
+            );
+        }
+    }
+    Ok(())
+}
+
+fn path_cstr(path: &Path) -> std::result::Result<CString, ffmpeg_next::Error> {
+    path.to_str()
+        .and_then(|path| CString::new(path).ok())
+        .ok_or(ffmpeg_next::Error::InvalidData)
+}
+
+// like format::input_with_dictionary, with the interrupt callback installed
+// before avformat_open_input so that probing can be aborted as well
+fn open_input(
+    path: &Path,
+    options: Option<Owned>,
+    interrupt: &Interrupt,
+) -> std::result::Result<Input, ffmpeg_next::Error> {
+    let path = path_cstr(path)?;
+    unsafe {
+        let mut ps = avformat_alloc_context();
+        if ps.is_null() {
+            return Err(ffmpeg_next::Error::Other { errno: ENOMEM });
+        }
+        (*ps).interrupt_callback = interrupt.callback();
+        let mut opts = options.unwrap_or_default().disown();
+        let res = avformat_open_input(&mut ps, path.as_ptr(), ptr::null_mut(), &mut opts);
+        Owned::own(opts);
+        // avformat_open_input frees the context on failure
+        if res < 0 {
+            return Err(ffmpeg_next::Error::from(res));
+        }
+        match avformat_find_stream_info(ps, ptr::null_mut()) {
+            r if r >= 0 => Ok(Input::wrap(ps)),
+            e => {
+                avformat_close_input(&mut ps);
+                Err(ffmpeg_next::Error::from(e))
+            }
+        }
+    }
+}
+
+// like format::output_as, with the interrupt callback that avio_open2 uses later.
+// Closing the session doesn't abort it, the trailer still has to be written.
+fn alloc_output(
+    path: &Path,
+    fmt: &str,
+    interrupt: &Interrupt,
+) -> std::result::Result<Output, ffmpeg_next::Error> {
+    let path = path_cstr(path)?;
+    let fmt = CString::new(fmt).map_err(|_| ffmpeg_next::Error::InvalidData)?;
+    unsafe {
+        let mut ps = ptr::null_mut();
+        let res =
+            avformat_alloc_output_context2(&mut ps, ptr::null_mut(), fmt.as_ptr(), path.as_ptr());
+        if res < 0 {
+            return Err(ffmpeg_next::Error::from(res));
+        }
+        (*ps).interrupt_callback = interrupt.output_callback();
+        Ok(Output::wrap(ps))
+    }
+}
+
+// avio_open2 for an allocated output, muxers like hls open their files themselves
+fn open_io(
+    out_fmt_ctx: &mut Output,
+    path: &Path,
+    options: Option<Owned>,
+) -> std::result::Result<(), ffmpeg_next::Error> {
+    let path = path_cstr(path)?;
+    unsafe {
+        let ps = out_fmt_ctx.as_mut_ptr();
+        if (*(*ps).oformat).flags & AVFMT_NOFILE as i32 != 0 || !(*ps).pb.is_null() {
+            return Ok(());
+        }
+        let mut opts = options.unwrap_or_default().disown();
+        let res = avio_open2(
+            &mut (*ps).pb,
+            path.as_ptr(),
+            AVIO_FLAG_WRITE,
+            &(*ps).interrupt_callback,
+            &mut opts,
+        );
+        Owned::own(opts);
+        match res {
+            r if r >= 0 => Ok(()),
+            e => Err(ffmpeg_next::Error::from(e)),
+        }
     }
 }
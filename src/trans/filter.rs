@@ -1,13 +1,18 @@
-use super::{ffmpeg::FmtCtx, sync::TimeGap};
+use super::{encoder::EncoderConf, ffmpeg::FmtCtx, output::write_all, stats::Stats};
+use crate::error::{is_drained, Error, Result};
+use std::ffi::{CStr, CString};
 use std::ops::{Deref, DerefMut};
+use std::os::raw::c_char;
 
 use ffmpeg_next::{
     decoder, encoder,
     filter::{self, Graph},
     format::Pixel,
     frame::Video,
-    picture, Packet,
+    media::Type,
+    picture, Packet, Rescale,
 };
+use ffmpeg_sys_next::{avfilter_graph_send_command, avfilter_link, AVFilterContext};
 
 pub struct FilterCtx {
     filter_graph: Graph,
@@ -15,9 +20,11 @@ pub struct FilterCtx {
 }
 
 impl FilterCtx {
-    pub fn init_filter(dec_ctx: &decoder::video::Video, osd: &str) -> Self {
-        // create filter graph
-        let mut filter_graph = filter::Graph::new();
+    pub fn init_filter(
+        dec_ctx: &decoder::video::Video,
+        osd: &str,
+        enc_conf: &EncoderConf,
+    ) -> Result<Self> {
         // init filter context
         let args = format!(
             "video_size={}x{}:pix_fmt={}:time_base={}/{}:pixel_aspect={}/{}",
@@ -30,24 +37,105 @@ impl FilterCtx {
             dec_ctx.aspect_ratio().numerator(),
             dec_ctx.aspect_ratio().denominator(),
         );
-        let buffesrc: ffmpeg_next::Filter = filter::find("buffer").unwrap();
-        let buffersink = filter::find("buffersink").unwrap();
-        let mut buffersrc_ctx = filter_graph.add(&buffesrc, "in", &args).unwrap();
+        FilterCtx::with_source(&args, dec_ctx.width(), dec_ctx.height(), osd, enc_conf)
+    }
+
+    // build the graph behind a stand-in source, so that a bad OSD is refused
+    // before a running session is replaced. Values that depend on the real
+    // picture are only checked by init_filter.
+    pub fn check(osd: &str, enc_conf: &EncoderConf) -> Result<()> {
+        let (width, height) = (1280, 720);
+        let args = format!(
+            "video_size={}x{}:pix_fmt=yuv420p:time_base=1/25:pixel_aspect=1/1",
+            width, height
+        );
+        FilterCtx::with_source(&args, width, height, osd, enc_conf).map(|_| ())
+    }
+
+    // buffer source with `src_args` -> OSD -> scale/fps/format stage -> sink
+    fn with_source(
+        src_args: &str,
+        width: u32,
+        height: u32,
+        osd: &str,
+        enc_conf: &EncoderConf,
+    ) -> Result<Self> {
+        // create filter graph
+        let mut filter_graph = filter::Graph::new();
+        let buffesrc: ffmpeg_next::Filter = filter::find("buffer").ok_or(Error::Filter(
+            "buffer filter not found",
+            ffmpeg_next::Error::FilterNotFound,
+        ))?;
+        let buffersink = filter::find("buffersink").ok_or(Error::Filter(
+            "buffersink filter not found",
+            ffmpeg_next::Error::FilterNotFound,
+        ))?;
+        let mut buffersrc_ctx = filter_graph
+            .add(&buffesrc, "in", src_args)
+            .map_err(|e| Error::Filter("failed to create buffer source", e))?;
         buffersrc_ctx.set_pixel_format(Pixel::YUV420P);
-        let mut buffersink_ctx = filter_graph.add(&buffersink, "out", "").unwrap();
-        buffersink_ctx.set_pixel_format(Pixel::YUV420P);
+        // "out" -> scale/fps/format stage -> "sink"
+        let mut prev = add_filter(&mut filter_graph, "null", "out", "")?;
+        for (i, (name, args)) in enc_conf.post_filters(width, height).iter().enumerate() {
+            let next = add_filter(&mut filter_graph, name, &format!("post{}", i), args)?;
+            link_filter(prev, next)?;
+            prev = next;
+        }
+        let mut buffersink_ctx = filter_graph
+            .add(&buffersink, "sink", "")
+            .map_err(|e| Error::Filter("failed to create buffer sink", e))?;
+        buffersink_ctx.set_pixel_format(enc_conf.output_format()?);
+        link_filter(prev, unsafe { buffersink_ctx.as_mut_ptr() })?;
         // init parser
         let parser = filter::graph::Parser::new(&mut filter_graph);
-        let parser = parser.output("in", 0).unwrap();
-        let parser = parser.input("out", 0).unwrap();
-        // filter description
-        parser.parse(osd).expect("Failed to parse drawtext");
+        let parser = parser
+            .output("in", 0)
+            .map_err(|e| Error::Filter("failed to bind graph input", e))?;
+        let parser = parser
+            .input("out", 0)
+            .map_err(|e| Error::Filter("failed to bind graph output", e))?;
+        // filter description, the graph may only scale
+        let osd = if osd.is_empty() { "null" } else { osd };
+        parser
+            .parse(osd)
+            .map_err(|e| Error::Filter("failed to parse filter description", e))?;
         // connect filters
-        filter_graph.validate().expect("Failed to connect filters");
-        FilterCtx {
+        filter_graph
+            .validate()
+            .map_err(|e| Error::Filter("failed to connect filters", e))?;
+        Ok(FilterCtx {
             filter_graph,
-            filter_frame: Video::new(Pixel::YUV420P, dec_ctx.width(), dec_ctx.height()),
+            filter_frame: Video::empty(),
+        })
+    }
+
+    // send a command to the running graph, e.g. target "drawtext", cmd "reinit", arg "text='new'"
+    pub fn send_command(&mut self, target: &str, cmd: &str, arg: &str) -> Result<String> {
+        let to_cstr = |s: &str| {
+            CString::new(s)
+                .map_err(|_| Error::Filter("nul byte in command", ffmpeg_next::Error::InvalidData))
+        };
+        let (target, cmd, arg) = (to_cstr(target)?, to_cstr(cmd)?, to_cstr(arg)?);
+        let mut res: [c_char; 256] = [0; 256];
+        let ret = unsafe {
+            avfilter_graph_send_command(
+                self.filter_graph.as_mut_ptr(),
+                target.as_ptr(),
+                cmd.as_ptr(),
+                arg.as_ptr(),
+                res.as_mut_ptr(),
+                res.len() as i32,
+                0,
+            )
+        };
+        if ret < 0 {
+            return Err(Error::Filter(
+                "failed to send filter command",
+                ffmpeg_next::Error::from(ret),
+            ));
         }
+        let res = unsafe { CStr::from_ptr(res.as_ptr()) };
+        Ok(res.to_string_lossy().into_owned())
     }
 
     pub fn filter_encode_write_frame(
@@ -55,83 +143,195 @@ impl FilterCtx {
         frame: &mut Video,
         enc_ctx: &mut encoder::Video,
         fmt_ctx: &mut FmtCtx,
-        time_gap: &mut TimeGap,
-    ) {
+        stats: &Stats,
+    ) -> Result<()> {
         // send frame to filter graph
-        let mut buffersrc_ctx = self.filter_graph.get("in").unwrap();
+        let mut buffersrc_ctx = graph_filter(&mut self.filter_graph, "in")?;
         buffersrc_ctx
             .source()
             .add(frame)
-            .expect("Error while feeding the filter_graph");
+            .map_err(|e| Error::Filter("failed to feed the filter graph", e))?;
+        self.drain(enc_ctx, fmt_ctx, stats)
+    }
+
+    // send EOF through the graph and the encoder, writing what is left in them
+    pub fn flush(
+        &mut self,
+        enc_ctx: &mut encoder::Video,
+        fmt_ctx: &mut FmtCtx,
+        stats: &Stats,
+    ) -> Result<()> {
+        let mut buffersrc_ctx = graph_filter(&mut self.filter_graph, "in")?;
+        buffersrc_ctx
+            .source()
+            .flush()
+            .map_err(|e| Error::Filter("failed to flush the filter graph", e))?;
+        self.drain(enc_ctx, fmt_ctx, stats)?;
+        enc_ctx
+            .send_eof()
+            .map_err(|e| Error::Codec("failed to flush encoder", e))?;
+        write_packets(enc_ctx, fmt_ctx, stats)
+    }
+
+    // encode and write every frame the graph has ready
+    fn drain(
+        &mut self,
+        enc_ctx: &mut encoder::Video,
+        fmt_ctx: &mut FmtCtx,
+        stats: &Stats,
+    ) -> Result<()> {
         loop {
-            // get frame from filter graph
-            let mut buffersink_ctx = self.filter_graph.get("out").unwrap();
+            // get frame from filter graph, a frame in may give none or several out
+            let mut buffersink_ctx = graph_filter(&mut self.filter_graph, "sink")?;
             let mut buffersink_ctx = buffersink_ctx.sink();
             let filter_frame = self.filter_frame.deref_mut();
             match buffersink_ctx.frame(filter_frame) {
                 Ok(()) => {}
-                Err(_) => {
-                    // println!("get frame failed from buffersink:{:?}");
-                    break;
-                }
+                Err(e) if is_drained(&e) => break,
+                Err(e) => return Err(Error::Filter("failed to pull from the filter graph", e)),
             };
+            // the encoder counts in its own time base
+            let sink_time_base = buffersink_ctx.time_base();
+            let enc_time_base = unsafe { (*enc_ctx.as_ptr()).time_base };
+            if let Some(pts) = self.filter_frame.pts() {
+                self.filter_frame
+                    .set_pts(Some(pts.rescale(sink_time_base, enc_time_base)));
+            }
+            Stats::incr(&stats.frames_filtered);
             self.filter_frame.set_kind(picture::Type::None);
             // mux frame
-            match self.encode_write_frame(enc_ctx, fmt_ctx, time_gap) {
-                Ok(()) => {
-                    self.filter_frame = Video::new(Pixel::YUV420P, frame.width(), frame.height())
-                }
-                Err(_) => {
-                    self.filter_frame = Video::new(Pixel::YUV420P, frame.width(), frame.height());
-                    break;
-                }
-            };
+            let written = self.encode_write_frame(enc_ctx, fmt_ctx, stats);
+            self.filter_frame = Video::empty();
+            // only fails for good once every output is gone, codec errors are
+            // counted already and the next frame is tried
+            match written {
+                Err(Error::Codec(..)) | Ok(()) => {}
+                Err(e) => return Err(e),
+            }
         }
+        Ok(())
     }
 
     fn encode_write_frame(
         &mut self,
         enc_ctx: &mut encoder::Video,
         fmt_ctx: &mut FmtCtx,
-        time_gap: &mut TimeGap,
-    ) -> Result<(), &str> {
-        let mut en_pkt = Packet::empty();
-        match enc_ctx.send_frame(self.filter_frame.deref()) {
-            Ok(_) => {
-                // println!("send_frame success");
+        stats: &Stats,
+    ) -> Result<()> {
+        if let Err(e) = enc_ctx.send_frame(self.filter_frame.deref()) {
+            Stats::incr(&stats.encode_errors);
+            Stats::incr(&stats.frames_dropped);
+            return Err(Error::Codec("failed to send frame to encoder", e));
+        }
+        write_packets(enc_ctx, fmt_ctx, stats)
+    }
+}
+
+// a filter of a configured graph by name
+pub fn graph_filter<'a>(graph: &'a mut Graph, name: &str) -> Result<filter::Context<'a>> {
+    graph.get(name).ok_or(Error::Filter(
+        "filter missing from the graph",
+        ffmpeg_next::Error::FilterNotFound,
+    ))
+}
+
+// write every packet the encoder has ready
+fn write_packets(enc_ctx: &mut encoder::Video, fmt_ctx: &mut FmtCtx, stats: &Stats) -> Result<()> {
+    let mut en_pkt = Packet::empty();
+    loop {
+        match enc_ctx.receive_packet(&mut en_pkt) {
+            Ok(()) => {}
+            Err(e) if is_drained(&e) => return Ok(()),
+            Err(e) => {
+                Stats::incr(&stats.encode_errors);
+                return Err(Error::Codec("failed to receive packet", e));
             }
-            Err(_) => return Err("send_frame failed"),
         };
-        loop {
-            match enc_ctx.receive_packet(&mut en_pkt) {
-                Ok(_) => {
-                    // println!("receive_packet success");
-                }
-                Err(_) => return Err("receive_packet failed"),
-            };
-            en_pkt.set_stream(0);
-            let out_fmt_timebase = fmt_ctx.out_fmt_ctx.stream(0).unwrap().time_base();
-            unsafe {
-                en_pkt.rescale_ts((*enc_ctx.as_ptr()).time_base, out_fmt_timebase);
-            }
-            en_pkt.set_dts(Some(
-                (time_gap.audio_time / f64::from(out_fmt_timebase)) as i64,
-            ));
-            en_pkt.set_pts(Some(
-                (time_gap.audio_time / f64::from(out_fmt_timebase)) as i64,
-            ));
-            let video_time: f64 = en_pkt.pts().unwrap() as f64 * f64::from(out_fmt_timebase);
-            time_gap.video_time = video_time;
-            // write to stream
-            match en_pkt.write(&mut fmt_ctx.out_fmt_ctx) {
-                Ok(_) => {
-                    // println!("----write packet success----");
-                }
-                Err(_) => {
-                    // println!("write frame failed");
-                    return Err("write frame failed");
-                }
-            };
+        en_pkt.set_stream(fmt_ctx.video_index);
+        Stats::incr(&stats.frames_encoded);
+        let enc_time_base = unsafe { (*enc_ctx.as_ptr()).time_base.into() };
+        write_all(
+            &mut fmt_ctx.outputs,
+            &en_pkt,
+            enc_time_base,
+            Type::Video,
+            stats,
+        )?;
+    }
+}
+
+fn add_filter(
+    filter_graph: &mut Graph,
+    name: &str,
+    inst: &str,
+    args: &str,
+) -> Result<*mut AVFilterContext> {
+    let filter = filter::find(name).ok_or(Error::Filter(
+        "filter not found",
+        ffmpeg_next::Error::FilterNotFound,
+    ))?;
+    let mut ctx = filter_graph
+        .add(&filter, inst, args)
+        .map_err(|e| Error::Filter("failed to create filter", e))?;
+    Ok(unsafe { ctx.as_mut_ptr() })
+}
+
+fn link_filter(src: *mut AVFilterContext, dst: *mut AVFilterContext) -> Result<()> {
+    match unsafe { avfilter_link(src, 0, dst, 0) } {
+        0 => Ok(()),
+        e => Err(Error::Filter(
+            "failed to link filters",
+            ffmpeg_next::Error::from(e),
+        )),
+    }
+}
+
+// quote a value for av_set_options_string style "key=value:key=value" lists,
+// `graph` escapes it once more for the parser of a filtergraph description
+pub fn escape_value(value: &str, graph: bool) -> String {
+    let quoted = format!("'{}'", value.replace('\'', "'\\''"));
+    match graph {
+        true => escape(&quoted, &['\\', '\'', '[', ']', ',', ';']),
+        false => quoted,
+    }
+}
+
+// drawtext text shown as is, without the %{...} expansion
+pub fn literal_text(text: &str) -> String {
+    escape(text, &['\\', '%'])
+}
+
+fn escape(value: &str, special: &[char]) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        if special.contains(&c) {
+            out.push('\\');
         }
+        out.push(c);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_value_for_option_lists() {
+        assert_eq!(escape_value("a:b", false), "'a:b'");
+        assert_eq!(escape_value("it's", false), r"'it'\''s'");
+    }
+
+    #[test]
+    fn escape_value_for_graph_descriptions() {
+        assert_eq!(escape_value("a,b", true), r"\'a\,b\'");
+        assert_eq!(escape_value("it's", true), r"\'it\'\\\'\'s\'");
+        assert_eq!(escape_value("[x];y", true), r"\'\[x\]\;y\'");
+    }
+
+    #[test]
+    fn literal_text_escapes_expansion() {
+        assert_eq!(literal_text(r"100% \o/"), r"100\% \\o/");
+        assert_eq!(literal_text("%{localtime}"), r"\%{localtime}");
     }
 }
@@ -0,0 +1,194 @@
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SegmentType {
+    #[default]
+    Mpegts,
+    Fmp4,
+}
+
+impl SegmentType {
+    fn extension(self) -> &'static str {
+        match self {
+            SegmentType::Mpegts => "ts",
+            SegmentType::Fmp4 => "m4s",
+        }
+    }
+}
+
+// HLS output, `output` is the path of the playlist and the segments are
+// written next to it
+#[derive(Deserialize, Debug, Clone)]
+pub struct HlsConf {
+    // target segment length in seconds, the GOP is aligned to it
+    #[serde(default = "default_segment_time")]
+    pub segment_time: u32,
+    // segments listed in the live playlist
+    #[serde(default = "default_list_size")]
+    pub list_size: u32,
+    // remove segments once they left the playlist
+    #[serde(default = "default_delete_segments")]
+    pub delete_segments: bool,
+    #[serde(default)]
+    pub segment_type: SegmentType,
+    // encode so that every segment starts on a keyframe, a remuxed session
+    // cuts on the keyframes of the camera and segments vary in length
+    #[serde(default = "default_align")]
+    pub align: bool,
+}
+
+fn default_segment_time() -> u32 {
+    4
+}
+
+fn default_list_size() -> u32 {
+    6
+}
+
+fn default_delete_segments() -> bool {
+    true
+}
+
+fn default_align() -> bool {
+    true
+}
+
+impl Default for HlsConf {
+    fn default() -> Self {
+        HlsConf {
+            segment_time: default_segment_time(),
+            list_size: default_list_size(),
+            delete_segments: default_delete_segments(),
+            segment_type: SegmentType::default(),
+            align: default_align(),
+        }
+    }
+}
+
+impl HlsConf {
+    // options for the hls muxer writing `playlist`
+    pub fn mux_options(&self, playlist: &Path) -> Vec<(&'static str, String)> {
+        let stem = playlist
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .unwrap_or("index");
+        let segment = format!("{}_%d.{}", stem, self.segment_type.extension());
+        let segment = match playlist.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.join(segment),
+            _ => segment.into(),
+        };
+        let mut flags = "+independent_segments".to_string();
+        if self.delete_segments {
+            flags.push_str("+delete_segments");
+        }
+        let mut options = vec![
+            ("hls_time", self.segment_time.to_string()),
+            ("hls_list_size", self.list_size.to_string()),
+            ("hls_flags", flags),
+            ("hls_segment_filename", segment.display().to_string()),
+            // a reopened output doesn't reuse segment names players may have cached
+            ("hls_start_number_source", "epoch".to_string()),
+        ];
+        if self.segment_type == SegmentType::Fmp4 {
+            options.push(("hls_segment_type", "fmp4".to_string()));
+            // relative to the playlist
+            options.push(("hls_fmp4_init_filename", format!("{}_init.mp4", stem)));
+        }
+        options
+    }
+}
+
+// file of the HLS output writing `playlist`, None for anything else in the directory
+pub fn file_path(playlist: &Path, name: &str) -> Option<PathBuf> {
+    let file_name = playlist.file_name()?.to_str()?;
+    let stem = playlist.file_stem()?.to_str()?;
+    let own = name == file_name
+        || name
+            .strip_prefix(stem)
+            .is_some_and(|rest| rest.starts_with('_'));
+    if !own || name.contains(['/', '\\']) {
+        return None;
+    }
+    Some(playlist.with_file_name(name))
+}
+
+// (content type, cache control) for a served HLS file
+pub fn content_type(name: &str) -> Option<(&'static str, &'static str)> {
+    let extension = Path::new(name).extension()?.to_str()?;
+    match extension {
+        // the live playlist changes with every segment
+        "m3u8" => Some(("application/vnd.apple.mpegurl", "no-cache")),
+        // segment names are never reused
+        "ts" => Some(("video/mp2t", "public, max-age=3600")),
+        "m4s" => Some(("video/iso.segment", "public, max-age=3600")),
+        // the init segment is rewritten when the output is reopened
+        "mp4" => Some(("video/mp4", "no-cache")),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mux_options_put_segments_next_to_the_playlist() {
+        let options = HlsConf::default().mux_options(Path::new("/srv/hls/live.m3u8"));
+        let option = |key| {
+            options
+                .iter()
+                .find(|(k, _)| *k == key)
+                .map(|(_, value)| value.as_str())
+        };
+        assert_eq!(option("hls_time"), Some("4"));
+        assert_eq!(option("hls_segment_filename"), Some("/srv/hls/live_%d.ts"));
+        assert_eq!(
+            option("hls_flags"),
+            Some("+independent_segments+delete_segments")
+        );
+        assert_eq!(option("hls_fmp4_init_filename"), None);
+    }
+
+    #[test]
+    fn mux_options_for_fmp4_segments() {
+        let conf = HlsConf {
+            segment_type: SegmentType::Fmp4,
+            ..HlsConf::default()
+        };
+        let options = conf.mux_options(Path::new("live.m3u8"));
+        assert!(options.contains(&("hls_segment_filename", "live_%d.m4s".to_string())));
+        assert!(options.contains(&("hls_fmp4_init_filename", "live_init.mp4".to_string())));
+    }
+
+    #[test]
+    fn file_path_serves_only_the_output_files() {
+        let playlist = Path::new("/srv/hls/live.m3u8");
+        let path = |name| file_path(playlist, name);
+        assert_eq!(path("live.m3u8"), Some(PathBuf::from("/srv/hls/live.m3u8")));
+        assert_eq!(
+            path("live_17.ts"),
+            Some(PathBuf::from("/srv/hls/live_17.ts"))
+        );
+        assert_eq!(
+            path("live_init.mp4"),
+            Some(PathBuf::from("/srv/hls/live_init.mp4"))
+        );
+        assert_eq!(path("other.ts"), None);
+        assert_eq!(path("lively.ts"), None);
+        assert_eq!(path("live_/../secret"), None);
+        assert_eq!(path("live_\\..\\secret"), None);
+    }
+
+    #[test]
+    fn content_type_by_extension() {
+        let playlist = content_type("live.m3u8").unwrap();
+        assert_eq!(playlist, ("application/vnd.apple.mpegurl", "no-cache"));
+        assert_eq!(content_type("live_1.ts").unwrap().0, "video/mp2t");
+        assert_eq!(content_type("live_1.m4s").unwrap().0, "video/iso.segment");
+        assert_eq!(content_type("live_init.mp4").unwrap().1, "no-cache");
+        assert_eq!(content_type("live.txt"), None);
+        assert_eq!(content_type("live"), None);
+    }
+}
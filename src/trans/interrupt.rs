@@ -0,0 +1,78 @@
+use ffmpeg_sys_next::AVIOInterruptCB;
+use std::os::raw::{c_int, c_void};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Instant;
+
+// shared between a session and the input context of its worker, lets blocking
+// FFmpeg I/O give up after `timeout_ms` or when the session quits. Every output
+// has one of its own that only times out, so its trailer is still written.
+pub struct Interrupt {
+    base: Instant,
+    timeout_ms: u64,
+    // ms since `base`
+    deadline: AtomicU64,
+    quit: AtomicBool,
+}
+
+impl Interrupt {
+    pub fn new(timeout_ms: u64) -> Self {
+        Interrupt {
+            base: Instant::now(),
+            timeout_ms,
+            deadline: AtomicU64::new(u64::MAX),
+            quit: AtomicBool::new(false),
+        }
+    }
+
+    pub fn timeout_ms(&self) -> u64 {
+        self.timeout_ms
+    }
+
+    fn now(&self) -> u64 {
+        self.base.elapsed().as_millis() as u64
+    }
+
+    // restart the timeout before a blocking call
+    pub fn arm(&self) {
+        let deadline = self.now().saturating_add(self.timeout_ms);
+        self.deadline.store(deadline, Ordering::Relaxed);
+    }
+
+    pub fn quit(&self) {
+        self.quit.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_quit(&self) -> bool {
+        self.quit.load(Ordering::Relaxed)
+    }
+
+    pub fn expired(&self) -> bool {
+        self.now() > self.deadline.load(Ordering::Relaxed)
+    }
+
+    // the callback keeps a raw pointer, `self` must outlive the format context
+    pub fn callback(&self) -> AVIOInterruptCB {
+        AVIOInterruptCB {
+            callback: Some(interrupt_cb),
+            opaque: self as *const Interrupt as *mut c_void,
+        }
+    }
+
+    // like `callback`, for output contexts that only give up on the timeout
+    pub fn output_callback(&self) -> AVIOInterruptCB {
+        AVIOInterruptCB {
+            callback: Some(output_cb),
+            opaque: self as *const Interrupt as *mut c_void,
+        }
+    }
+}
+
+extern "C" fn interrupt_cb(opaque: *mut c_void) -> c_int {
+    let interrupt = unsafe { &*(opaque as *const Interrupt) };
+    (interrupt.is_quit() || interrupt.expired()) as c_int
+}
+
+extern "C" fn output_cb(opaque: *mut c_void) -> c_int {
+    let interrupt = unsafe { &*(opaque as *const Interrupt) };
+    interrupt.expired() as c_int
+}
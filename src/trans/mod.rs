@@ -1,3 +1,14 @@
+pub mod audio;
+pub mod container;
+pub mod encoder;
 pub mod ffmpeg;
 pub mod filter;
+pub mod hls;
+pub mod interrupt;
+pub mod osd;
+pub mod output;
+pub mod reconnect;
+pub mod record;
+pub mod source;
+pub mod stats;
 pub mod sync;
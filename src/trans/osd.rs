@@ -0,0 +1,282 @@
+use serde::Deserialize;
+use std::path::Path;
+
+use super::filter::{escape_value, literal_text};
+
+// uploaded images are stored here, see `upload_handler`
+pub const UPLOAD_DIR: &str = "uploads";
+
+// `osd` field of the request: either a raw filtergraph description or a list
+// of overlays compiled into a filtergraph. Text overlays become drawtext
+// filters named "drawtext@osd<N>", images become "overlay@osd<N>".
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Osd {
+    Raw(String),
+    Overlays(Vec<Overlay>),
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Overlay {
+    Text(TextOverlay),
+    Image(ImageOverlay),
+}
+
+impl Default for Osd {
+    fn default() -> Self {
+        Osd::Raw(String::new())
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Anchor {
+    TopLeft,
+    Top,
+    TopRight,
+    Left,
+    Center,
+    Right,
+    BottomLeft,
+    Bottom,
+    BottomRight,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct TextOverlay {
+    pub text: String,
+    // drawtext expressions, take precedence over `anchor`
+    pub x: Option<String>,
+    pub y: Option<String>,
+    #[serde(default = "default_anchor")]
+    pub anchor: Anchor,
+    // distance to the frame border when anchored, in pixels
+    #[serde(default = "default_margin")]
+    pub margin: u32,
+    pub font_file: Option<String>,
+    #[serde(default = "default_size")]
+    pub size: u32,
+    #[serde(default = "default_color")]
+    pub color: String,
+    // draw a background box behind the text
+    pub box_color: Option<String>,
+    #[serde(default = "default_box_opacity")]
+    pub box_opacity: f32,
+    #[serde(default = "default_opacity")]
+    pub opacity: f32,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageSrc {
+    // file on the server
+    Path(String),
+    // name given to POST /upload/{name}
+    Upload(String),
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ImageOverlay {
+    pub image: ImageSrc,
+    // overlay expressions, take precedence over `anchor`
+    pub x: Option<String>,
+    pub y: Option<String>,
+    #[serde(default = "default_anchor")]
+    pub anchor: Anchor,
+    #[serde(default = "default_margin")]
+    pub margin: u32,
+    // scale factor of the image, or target width keeping aspect ratio
+    pub scale: Option<f32>,
+    pub width: Option<u32>,
+    #[serde(default = "default_opacity")]
+    pub opacity: f32,
+}
+
+fn default_anchor() -> Anchor {
+    Anchor::TopLeft
+}
+
+fn default_margin() -> u32 {
+    10
+}
+
+fn default_size() -> u32 {
+    24
+}
+
+fn default_color() -> String {
+    "white".to_string()
+}
+
+fn default_box_opacity() -> f32 {
+    0.5
+}
+
+fn default_opacity() -> f32 {
+    1.0
+}
+
+impl Osd {
+    pub fn is_empty(&self) -> bool {
+        match self {
+            Osd::Raw(desc) => desc.is_empty(),
+            Osd::Overlays(overlays) => overlays.is_empty(),
+        }
+    }
+
+    // filtergraph description for FilterCtx::init_filter
+    pub fn to_filter(&self) -> String {
+        match self {
+            Osd::Raw(desc) => desc.clone(),
+            Osd::Overlays(overlays) => {
+                // chain "[in] -> [v0] -> [v1] ... -> [out]"
+                let mut chains = Vec::new();
+                for (i, overlay) in overlays.iter().enumerate() {
+                    let input = match i {
+                        0 => "in".to_string(),
+                        _ => format!("v{}", i - 1),
+                    };
+                    let output = match i + 1 == overlays.len() {
+                        true => "out".to_string(),
+                        false => format!("v{}", i),
+                    };
+                    match overlay {
+                        Overlay::Text(text) => {
+                            chains.push(format!("[{}]{}[{}]", input, text.to_filter(i), output))
+                        }
+                        Overlay::Image(image) => {
+                            chains.push(format!("{}[img{}]", image.source_filter(), i));
+                            chains.push(format!(
+                                "[{}][img{}]{}[{}]",
+                                input,
+                                i,
+                                image.to_filter(i),
+                                output
+                            ));
+                        }
+                    }
+                }
+                chains.join(";")
+            }
+        }
+    }
+}
+
+fn join_args(args: &[(&str, String)]) -> String {
+    args.iter()
+        .map(|(key, value)| format!("{}={}", key, escape_value(value, true)))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+// x/y expressions for an anchor, `main` and `obj` are the expression names of
+// the frame size and of the drawn object size
+fn anchor_position(
+    anchor: Anchor,
+    margin: u32,
+    main: (&str, &str),
+    obj: (&str, &str),
+) -> (String, String) {
+    let (col, row) = match anchor {
+        Anchor::TopLeft => (0, 0),
+        Anchor::Top => (1, 0),
+        Anchor::TopRight => (2, 0),
+        Anchor::Left => (0, 1),
+        Anchor::Center => (1, 1),
+        Anchor::Right => (2, 1),
+        Anchor::BottomLeft => (0, 2),
+        Anchor::Bottom => (1, 2),
+        Anchor::BottomRight => (2, 2),
+    };
+    let place = |pos: u8, main: &str, obj: &str| match pos {
+        0 => margin.to_string(),
+        1 => format!("({}-{})/2", main, obj),
+        _ => format!("{}-{}-{}", main, obj, margin),
+    };
+    (place(col, main.0, obj.0), place(row, main.1, obj.1))
+}
+
+impl TextOverlay {
+    fn to_filter(&self, idx: usize) -> String {
+        let (x, y) = anchor_position(self.anchor, self.margin, ("w", "h"), ("text_w", "text_h"));
+        let x = self.x.clone().unwrap_or(x);
+        let y = self.y.clone().unwrap_or(y);
+        let mut args = vec![
+            ("text", literal_text(&self.text)),
+            ("x", x),
+            ("y", y),
+            ("fontsize", self.size.to_string()),
+            ("fontcolor", format!("{}@{}", self.color, self.opacity)),
+        ];
+        if let Some(font_file) = &self.font_file {
+            args.push(("fontfile", font_file.clone()));
+        }
+        if let Some(box_color) = &self.box_color {
+            args.push(("box", "1".to_string()));
+            args.push(("boxcolor", format!("{}@{}", box_color, self.box_opacity)));
+            args.push(("boxborderw", (self.size / 4).to_string()));
+        }
+        format!("drawtext@osd{}={}", idx, join_args(&args))
+    }
+}
+
+impl ImageOverlay {
+    pub fn path(&self) -> String {
+        match &self.image {
+            ImageSrc::Path(path) => path.clone(),
+            ImageSrc::Upload(name) => Path::new(UPLOAD_DIR).join(name).display().to_string(),
+        }
+    }
+
+    // movie source producing the prepared rgba image
+    fn source_filter(&self) -> String {
+        let mut chain = vec![format!("movie={}", join_args(&[("filename", self.path())]))];
+        if let Some(width) = self.width {
+            chain.push(format!("scale={}:-1", width));
+        } else if let Some(scale) = self.scale {
+            chain.push(format!("scale=iw*{}:ih*{}", scale, scale));
+        }
+        chain.push("format=rgba".to_string());
+        if self.opacity < 1.0 {
+            chain.push(format!("colorchannelmixer=aa={}", self.opacity));
+        }
+        chain.join(",")
+    }
+
+    fn to_filter(&self, idx: usize) -> String {
+        let (x, y) = anchor_position(self.anchor, self.margin, ("W", "H"), ("w", "h"));
+        let x = self.x.clone().unwrap_or(x);
+        let y = self.y.clone().unwrap_or(y);
+        format!("overlay@osd{}={}", idx, join_args(&[("x", x), ("y", y)]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_overlay_is_shown_literally() {
+        let text = TextOverlay {
+            text: "50%: done".to_string(),
+            x: None,
+            y: None,
+            anchor: default_anchor(),
+            margin: default_margin(),
+            font_file: None,
+            size: default_size(),
+            color: default_color(),
+            box_color: None,
+            box_opacity: default_box_opacity(),
+            opacity: default_opacity(),
+        };
+        let filter = Osd::Overlays(vec![Overlay::Text(text)]).to_filter();
+        assert!(
+            filter.starts_with(r"[in]drawtext@osd0=text=\'50\\%: done\':x=\'10\':y=\'10\'"),
+            "{}",
+            filter
+        );
+        assert!(filter.ends_with("[out]"), "{}", filter);
+    }
+}
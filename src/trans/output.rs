@@ -0,0 +1,441 @@
+use crossbeam_channel::{bounded, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use ffmpeg_next::{
+    dictionary::Owned, format::context::Output, media::Type, util::error::EINVAL, Packet, Rational,
+};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use super::{
+    container::Container,
+    ffmpeg::StreamCtx,
+    hls::HlsConf,
+    interrupt::Interrupt,
+    reconnect::{Backoff, RetryConf},
+    record::{RecordConf, Recorder},
+    stats::{State, Stats},
+    sync::{Rebase, SyncCtx},
+};
+use crate::error::{Error, Result};
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct OutputConf {
+    pub output: String,
+    // output container, guessed from the output URL when not given
+    #[serde(default)]
+    pub format: Container,
+    #[serde(default)]
+    pub out_options: HashMap<String, String>,
+    #[serde(default)]
+    pub output_retry: RetryConf,
+    #[serde(default)]
+    pub hls: HlsConf,
+    // record into `output` as a directory of files
+    pub record: Option<RecordConf>,
+}
+
+impl OutputConf {
+    pub fn container(&self) -> Container {
+        match (&self.record, self.format) {
+            // readable even when the process dies mid-file
+            (Some(_), Container::Auto) => Container::Matroska,
+            (_, format) => format.resolve(&self.output),
+        }
+    }
+
+    // file to open, a new one for every call when recording
+    pub fn path(&self) -> PathBuf {
+        match &self.record {
+            Some(record) => record.file_path(Path::new(&self.output), self.container().extension()),
+            None => PathBuf::from(&self.output),
+        }
+    }
+
+    // segment length in seconds for outputs that want the GOP aligned to their
+    // segments, only the encoder can do that
+    pub fn segment_time(&self) -> Option<u32> {
+        match (&self.record, self.container()) {
+            (Some(record), _) if record.align => Some(record.segment_time),
+            (Some(_), _) => None,
+            (None, Container::Hls) if self.hls.align => Some(self.hls.segment_time),
+            _ => None,
+        }
+    }
+
+    // protocol options for avio_open2
+    pub fn out_dict(&self) -> Option<Owned<'static>> {
+        if self.out_options.is_empty() {
+            return None;
+        }
+        let mut dict = Owned::new();
+        for (key, value) in &self.out_options {
+            dict.set(key, value);
+        }
+        Some(dict)
+    }
+
+    // muxer options for write_header, out_options override the container defaults
+    pub fn mux_dict(&self) -> Owned<'static> {
+        let mut dict = Owned::new();
+        for (key, value) in self.container().mux_options() {
+            dict.set(key, value);
+        }
+        if self.container() == Container::Hls {
+            for (key, value) in self.hls.mux_options(Path::new(&self.output)) {
+                dict.set(key, &value);
+            }
+        }
+        for (key, value) in &self.out_options {
+            dict.set(key, value);
+        }
+        dict
+    }
+}
+
+enum Link {
+    Up,
+    // a write failed, reopened in the background
+    Down(Reconnect),
+    // out of retries, skipped for the rest of the session
+    Gone,
+}
+
+// an output context opened on a thread of its own after the backoff delay, so
+// that a slow or dead destination doesn't hold up the session
+struct Reconnect {
+    // dropped with the link, a thread still waiting gives up
+    _cancel: Sender<()>,
+    opened: Receiver<Result<(Output, PathBuf)>>,
+}
+
+// one destination of a session with its own muxer, output clock and failure
+// state, a lost destination is retried without holding up the others
+pub struct OutputCtx {
+    pub conf: OutputConf,
+    pub fmt_ctx: Output,
+    pub sync: SyncCtx,
+    // file or URL `fmt_ctx` writes to
+    path: PathBuf,
+    // position in the session, for status
+    index: usize,
+    link: Link,
+    backoff: Backoff,
+    recorder: Option<Recorder>,
+    // times out the I/O of this output only, `fmt_ctx` and a reconnect keep a
+    // pointer to it so it's dropped last
+    interrupt: Arc<Interrupt>,
+}
+
+impl OutputCtx {
+    pub fn new(
+        index: usize,
+        conf: OutputConf,
+        fmt_ctx: Output,
+        path: PathBuf,
+        interrupt: Arc<Interrupt>,
+    ) -> Self {
+        let backoff = conf.output_retry.backoff();
+        let recorder = conf.record.as_ref().map(|record| record.recorder());
+        OutputCtx {
+            conf,
+            fmt_ctx,
+            sync: SyncCtx::default(),
+            path,
+            index,
+            link: Link::Up,
+            backoff,
+            recorder,
+            interrupt,
+        }
+    }
+
+    // open the output and write the header, one that can't be reached yet is
+    // retried like a lost one. The error is returned either way.
+    fn start(&mut self, stats: &Stats) -> Result<()> {
+        let started =
+            StreamCtx::out_io_open(&mut self.fmt_ctx, &self.path, &self.conf, &self.interrupt)
+                .and_then(|_| write_header(&mut self.fmt_ctx, &self.conf));
+        match started {
+            Ok(_) => {
+                stats.output(self.index, &self.conf.output, State::Running);
+                self.prune();
+                Ok(())
+            }
+            Err(e) => {
+                log::warn!("output {} not started: {}", self.conf.output, e);
+                if !self.wait(stats) {
+                    log::error!("output {} given up", self.conf.output);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    pub fn is_up(&self) -> bool {
+        matches!(self.link, Link::Up)
+    }
+
+    fn is_gone(&self) -> bool {
+        matches!(self.link, Link::Gone)
+    }
+
+    // write a packet given in `time_base` to the same stream of this output,
+    // a recording moves on to the next file at a keyframe
+    fn write(
+        &mut self,
+        mut packet: Packet,
+        time_base: Rational,
+        medium: Type,
+        stats: &Stats,
+    ) -> Result<()> {
+        // every output gets the full I/O timeout
+        self.interrupt.arm();
+        let cut = match (&mut self.recorder, packet.pts().or(packet.dts())) {
+            (Some(recorder), Some(ts)) => {
+                medium == Type::Video && packet.is_key() && recorder.cut(ts, time_base)
+            }
+            _ => false,
+        };
+        if cut {
+            self.rotate()?;
+        }
+        let out_time_base = self
+            .fmt_ctx
+            .stream(packet.stream())
+            .ok_or(Error::Mux(
+                "packet for a missing output stream",
+                ffmpeg_next::Error::StreamNotFound,
+            ))?
+            .time_base();
+        packet.rescale_ts(time_base, out_time_base);
+        self.sync.output(&mut packet, out_time_base, medium);
+        stats.written(packet.size());
+        if let Some(recorder) = &mut self.recorder {
+            recorder.written(packet.size());
+        }
+        packet.write(&mut self.fmt_ctx).map_err(|e| match medium {
+            Type::Video => Error::Mux("failed to write video packet", e),
+            _ => Error::Mux("failed to write audio packet", e),
+        })?;
+        Ok(())
+    }
+
+    // finish the current file of a recording and continue in a new one
+    fn rotate(&mut self) -> Result<()> {
+        self.finish()?;
+        self.reopen()?;
+        log::info!("recording {} continues in a new file", self.conf.output);
+        self.prune();
+        Ok(())
+    }
+
+    // open the output again with the same stream layout, starting at zero
+    fn reopen(&mut self) -> Result<()> {
+        let (mut fmt_ctx, path) = StreamCtx::out_alloc(&self.conf, &self.fmt_ctx, &self.interrupt)?;
+        StreamCtx::out_io_open(&mut fmt_ctx, &path, &self.conf, &self.interrupt)?;
+        write_header(&mut fmt_ctx, &self.conf)?;
+        self.replace(fmt_ctx, path);
+        Ok(())
+    }
+
+    fn replace(&mut self, fmt_ctx: Output, path: PathBuf) {
+        self.fmt_ctx = fmt_ctx;
+        self.path = path;
+        self.sync.rebase(Rebase::Zero);
+    }
+
+    // apply the retention of a recording, failures only cost disk space
+    fn prune(&self) {
+        if let Some(record) = &self.conf.record {
+            let dir = Path::new(&self.conf.output);
+            let extension = self.conf.container().extension();
+            if let Err(e) = record.prune(dir, extension, &self.path) {
+                log::warn!("pruning {} failed: {}", self.conf.output, e);
+            }
+        }
+    }
+
+    fn lost(&mut self, err: Error, stats: &Stats) -> Result<()> {
+        log::warn!("output {} lost: {}", self.conf.output, err);
+        self.backoff = self.conf.output_retry.backoff();
+        self.give_up(err, stats)
+    }
+
+    // wait for the next reopen, fails when the retries are used up
+    fn give_up(&mut self, err: Error, stats: &Stats) -> Result<()> {
+        if self.wait(stats) {
+            return Ok(());
+        }
+        log::error!("output {} given up: {}", self.conf.output, err);
+        Err(err)
+    }
+
+    // schedule the next reopen, false when the retries are used up
+    fn wait(&mut self, stats: &Stats) -> bool {
+        match self.backoff.next() {
+            Some(delay) => {
+                self.link = Link::Down(self.reconnect(delay));
+                stats.output(self.index, &self.conf.output, State::Reconnecting);
+                true
+            }
+            None => {
+                self.link = Link::Gone;
+                stats.output(self.index, &self.conf.output, State::Failed);
+                false
+            }
+        }
+    }
+
+    // open the output again after `delay` without blocking the session, the
+    // streams are copied here as the lost context stays with this output
+    fn reconnect(&self, delay: Duration) -> Reconnect {
+        let (cancel, cancelled) = bounded::<()>(0);
+        let (done, opened) = bounded(1);
+        match StreamCtx::out_alloc(&self.conf, &self.fmt_ctx, &self.interrupt) {
+            Ok((mut fmt_ctx, path)) => {
+                let conf = self.conf.clone();
+                let interrupt = self.interrupt.clone();
+                thread::spawn(move || {
+                    // the session is over when the link is dropped before the delay
+                    if !matches!(
+                        cancelled.recv_timeout(delay),
+                        Err(RecvTimeoutError::Timeout)
+                    ) {
+                        return;
+                    }
+                    let reopened = StreamCtx::out_io_open(&mut fmt_ctx, &path, &conf, &interrupt)
+                        .and_then(|_| write_header(&mut fmt_ctx, &conf))
+                        .map(|_| (fmt_ctx, path));
+                    let _ = done.send(reopened);
+                });
+            }
+            Err(e) => {
+                let _ = done.send(Err(e));
+            }
+        }
+        Reconnect {
+            _cancel: cancel,
+            opened,
+        }
+    }
+
+    // take over an output the reconnect thread opened
+    fn retry(&mut self, stats: &Stats) -> Result<()> {
+        let reopened = match &self.link {
+            Link::Down(reconnect) => match reconnect.opened.try_recv() {
+                Ok(reopened) => reopened,
+                Err(TryRecvError::Empty) => return Ok(()),
+                // the thread panicked
+                Err(TryRecvError::Disconnected) => Err(Error::Open(
+                    self.conf.output.clone(),
+                    ffmpeg_next::Error::Exit,
+                )),
+            },
+            _ => return Ok(()),
+        };
+        match reopened {
+            Ok((fmt_ctx, path)) => {
+                self.replace(fmt_ctx, path);
+                if let Some(recorder) = &mut self.recorder {
+                    recorder.reset();
+                }
+                self.link = Link::Up;
+                Stats::incr(&stats.output_reconnects);
+                stats.output(self.index, &self.conf.output, State::Running);
+                Ok(())
+            }
+            Err(e) => {
+                log::warn!("reconnect {} failed: {}", self.conf.output, e);
+                self.give_up(e, stats)
+            }
+        }
+    }
+
+    // finalise the container, a lost output has nothing to finish
+    pub fn finish(&mut self) -> Result<()> {
+        if !self.is_up() {
+            return Ok(());
+        }
+        self.interrupt.arm();
+        self.fmt_ctx
+            .write_trailer()
+            .map_err(|e| Error::Mux("failed to write trailer", e))
+    }
+}
+
+fn write_header(fmt_ctx: &mut Output, conf: &OutputConf) -> Result<()> {
+    fmt_ctx
+        .write_header_with(conf.mux_dict())
+        .map_err(|e| Error::Mux("failed to write header", e))
+}
+
+// start every output, setup fails only when none of them comes up
+pub fn start_all(outputs: &mut [OutputCtx], stats: &Stats) -> Result<()> {
+    let mut last_err = None;
+    for output in outputs.iter_mut() {
+        if let Err(e) = output.start(stats) {
+            last_err = Some(e);
+        }
+    }
+    match last_err {
+        Some(e) if !outputs.iter().any(|output| output.is_up()) => Err(e),
+        _ => Ok(()),
+    }
+}
+
+// write a packet given in `time_base` to every live output, the stream index is
+// the same in all of them. Packets with invalid timestamps are dropped, any other
+// write failure takes only that output down. Fails once every output is gone.
+pub fn write_all(
+    outputs: &mut [OutputCtx],
+    packet: &Packet,
+    time_base: Rational,
+    medium: Type,
+    stats: &Stats,
+) -> Result<()> {
+    let mut last_err = None;
+    for output in outputs.iter_mut() {
+        if let Err(e) = output.retry(stats) {
+            last_err = Some(e);
+        }
+        if !output.is_up() {
+            continue;
+        }
+        match output.write(packet.clone(), time_base, medium, stats) {
+            Ok(()) => {}
+            Err(Error::Mux(_, ffmpeg_next::Error::Other { errno: EINVAL })) => {
+                Stats::incr(&stats.frames_dropped);
+            }
+            Err(e) => {
+                if let Err(e) = output.lost(e, stats) {
+                    last_err = Some(e);
+                }
+            }
+        }
+    }
+
+    match outputs.iter().find(|output| output.is_up()) {
+        Some(output) => {
+            stats.sync(&output.sync);
+            if stats.state() == State::Reconnecting {
+                stats.resumed();
+            }
+        }
+        None if outputs.iter().all(|output| output.is_gone()) => {
+            return Err(last_err.unwrap_or_else(|| Error::Probe("every output failed".to_string())));
+        }
+        None => stats.reconnecting(),
+    }
+    Ok(())
+}
+
+// session time of the latest video packet written anywhere, for a generated silent track
+pub fn video_clock(outputs: &[OutputCtx]) -> Option<i64> {
+    outputs
+        .iter()
+        .filter_map(|output| output.sync.video_clock())
+        .max()
+}
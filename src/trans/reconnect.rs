@@ -0,0 +1,110 @@
+use serde::Deserialize;
+use std::time::Duration;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct RetryConf {
+    // consecutive I/O errors before the connection is considered lost
+    #[serde(default = "default_max_errors")]
+    pub max_errors: u32,
+    #[serde(default = "default_initial_backoff")]
+    pub initial_backoff_ms: u64,
+    #[serde(default = "default_max_backoff")]
+    pub max_backoff_ms: u64,
+    // give up after this many failed attempts, retry forever when unset
+    #[serde(default)]
+    pub max_retries: Option<u32>,
+}
+
+fn default_max_errors() -> u32 {
+    50
+}
+
+fn default_initial_backoff() -> u64 {
+    500
+}
+
+fn default_max_backoff() -> u64 {
+    30_000
+}
+
+impl Default for RetryConf {
+    fn default() -> Self {
+        RetryConf {
+            max_errors: default_max_errors(),
+            initial_backoff_ms: default_initial_backoff(),
+            max_backoff_ms: default_max_backoff(),
+            max_retries: None,
+        }
+    }
+}
+
+impl RetryConf {
+    pub fn backoff(&self) -> Backoff {
+        Backoff {
+            delay: self.initial_backoff_ms,
+            max_delay: self.max_backoff_ms,
+            retries_left: self.max_retries,
+        }
+    }
+}
+
+// exponential backoff delays, doubling up to `max_backoff_ms`
+pub struct Backoff {
+    delay: u64,
+    max_delay: u64,
+    retries_left: Option<u32>,
+}
+
+impl Iterator for Backoff {
+    type Item = Duration;
+
+    fn next(&mut self) -> Option<Duration> {
+        if let Some(retries_left) = &mut self.retries_left {
+            if *retries_left == 0 {
+                return None;
+            }
+            *retries_left -= 1;
+        }
+        let delay = Duration::from_millis(self.delay);
+        self.delay = self.delay.saturating_mul(2).min(self.max_delay);
+        Some(delay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conf(initial: u64, max: u64, retries: Option<u32>) -> RetryConf {
+        RetryConf {
+            initial_backoff_ms: initial,
+            max_backoff_ms: max,
+            max_retries: retries,
+            ..RetryConf::default()
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let delays: Vec<u64> = conf(500, 3000, None)
+            .backoff()
+            .take(5)
+            .map(|delay| delay.as_millis() as u64)
+            .collect();
+        assert_eq!(delays, [500, 1000, 2000, 3000, 3000]);
+    }
+
+    #[test]
+    fn backoff_stops_after_max_retries() {
+        assert_eq!(conf(500, 3000, Some(2)).backoff().count(), 2);
+        assert_eq!(conf(500, 3000, Some(0)).backoff().next(), None);
+    }
+
+    #[test]
+    fn backoff_saturates() {
+        let mut backoff = conf(u64::MAX / 2 + 1, u64::MAX, None).backoff();
+        backoff.next();
+        assert_eq!(backoff.next(), Some(Duration::from_millis(u64::MAX)));
+        assert_eq!(backoff.next(), Some(Duration::from_millis(u64::MAX)));
+    }
+}
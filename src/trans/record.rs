@@ -0,0 +1,278 @@
+use ffmpeg_next::{Rational, Rescale};
+use serde::Deserialize;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const US: Rational = Rational(1, 1_000_000);
+const MB: u64 = 1_000_000;
+// 20261018T142530.123Z
+const STAMP_LEN: usize = 20;
+
+// continuous recording, `output` is a directory of files named by their UTC
+// start time
+#[derive(Deserialize, Debug, Clone)]
+pub struct RecordConf {
+    // file length in seconds, cut on the first keyframe after it
+    #[serde(default = "default_segment_time")]
+    pub segment_time: u32,
+    // also cut once a file reaches this size
+    pub max_size_mb: Option<u64>,
+    // delete files older than this
+    pub max_age_hours: Option<u64>,
+    // delete the oldest files while the directory holds more than this
+    pub max_total_mb: Option<u64>,
+    // encode so that every file starts on a keyframe right at `segment_time`, a
+    // remuxed session cuts on the first camera keyframe after it
+    #[serde(default = "default_align")]
+    pub align: bool,
+}
+
+fn default_segment_time() -> u32 {
+    600
+}
+
+fn default_align() -> bool {
+    true
+}
+
+impl Default for RecordConf {
+    fn default() -> Self {
+        RecordConf {
+            segment_time: default_segment_time(),
+            max_size_mb: None,
+            max_age_hours: None,
+            max_total_mb: None,
+            align: default_align(),
+        }
+    }
+}
+
+impl RecordConf {
+    // a new file in `dir` starting now, numbered if one started in the same
+    // millisecond so that it is never overwritten
+    pub fn file_path(&self, dir: &Path, extension: &str) -> PathBuf {
+        let stamp = timestamp(SystemTime::now());
+        let mut path = dir.join(format!("{}.{}", stamp, extension));
+        let mut n = 0;
+        while path.exists() {
+            n += 1;
+            path = dir.join(format!("{}-{}.{}", stamp, n, extension));
+        }
+        path
+    }
+
+    pub fn recorder(&self) -> Recorder {
+        Recorder {
+            segment_us: self.segment_time as i64 * 1_000_000,
+            max_bytes: self.max_size_mb.map(|mb| mb * MB),
+            start: None,
+            bytes: 0,
+        }
+    }
+
+    // delete the oldest recordings beyond max age or total size, `current` is
+    // the file being written, it counts towards the total but is always kept
+    pub fn prune(&self, dir: &Path, extension: &str, current: &Path) -> io::Result<()> {
+        if self.max_age_hours.is_none() && self.max_total_mb.is_none() {
+            return Ok(());
+        }
+        let mut files = Vec::new();
+        let mut total = 0;
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if !is_recording(&path, extension) {
+                continue;
+            }
+            let metadata = entry.metadata()?;
+            total += metadata.len();
+            if path.file_name() != current.file_name() {
+                files.push((path, metadata.len(), metadata.modified()?));
+            }
+        }
+        // names sort by start time
+        files.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let max_age = self
+            .max_age_hours
+            .map(|hours| Duration::from_secs(hours * 3600));
+        let max_total = self.max_total_mb.map(|mb| mb * MB);
+        for (path, size, modified) in files {
+            let expired =
+                max_age.is_some_and(|max_age| modified.elapsed().is_ok_and(|age| age > max_age));
+            let over = max_total.is_some_and(|max_total| total > max_total);
+            if !expired && !over {
+                break;
+            }
+            fs::remove_file(&path)?;
+            log::info!("recording {} removed", path.display());
+            total -= size;
+        }
+        Ok(())
+    }
+}
+
+// when to start the next file of a recording
+pub struct Recorder {
+    segment_us: i64,
+    max_bytes: Option<u64>,
+    // session time the current file started at, µs
+    start: Option<i64>,
+    bytes: u64,
+}
+
+impl Recorder {
+    // called for every video keyframe, true if the file should end before it
+    pub fn cut(&mut self, ts: i64, time_base: Rational) -> bool {
+        let time = ts.rescale(time_base, US);
+        let start = *self.start.get_or_insert(time);
+        let long = time - start >= self.segment_us;
+        let large = self.max_bytes.is_some_and(|max| self.bytes >= max);
+        if long || large {
+            // the next file starts with this keyframe
+            self.start = Some(time);
+            self.bytes = 0;
+        }
+        long || large
+    }
+
+    pub fn written(&mut self, bytes: usize) {
+        self.bytes += bytes as u64;
+    }
+
+    // the file was reopened, it starts over at the next keyframe
+    pub fn reset(&mut self) {
+        self.start = None;
+        self.bytes = 0;
+    }
+}
+
+// a file named by file_path, the stamp may be followed by -<n>
+fn is_recording(path: &Path, extension: &str) -> bool {
+    let stem = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or_default();
+    let stamp = stem.get(..STAMP_LEN).is_some_and(|stamp| {
+        stamp.char_indices().all(|(i, c)| match i {
+            8 => c == 'T',
+            15 => c == '.',
+            19 => c == 'Z',
+            _ => c.is_ascii_digit(),
+        })
+    });
+    let n = match stem.get(STAMP_LEN..) {
+        Some("") => true,
+        Some(n) => n
+            .strip_prefix('-')
+            .is_some_and(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit())),
+        None => false,
+    };
+    path.extension().and_then(|ext| ext.to_str()) == Some(extension) && stamp && n
+}
+
+// UTC wall clock as 20261018T142530.123Z
+fn timestamp(time: SystemTime) -> String {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let (secs, millis) = (since.as_secs(), since.subsec_millis());
+    let (days, secs) = ((secs / 86_400) as i64, secs % 86_400);
+    // civil date from days since 1970-01-01
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60,
+        millis
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(ms: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn timestamp_is_utc_with_milliseconds() {
+        assert_eq!(timestamp(at(0)), "19700101T000000.000Z");
+        assert_eq!(timestamp(at(951_868_799_999)), "20000229T235959.999Z");
+        assert_eq!(timestamp(at(1_792_333_530_123)), "20261018T142530.123Z");
+    }
+
+    #[test]
+    fn is_recording_matches_file_path_names() {
+        let is = |name: &str| is_recording(Path::new(name), "mkv");
+        assert!(is("/rec/20261018T142530.123Z.mkv"));
+        assert!(is("/rec/20261018T142530.123Z-2.mkv"));
+        assert!(!is("/rec/20261018T142530.123Z.mp4"));
+        assert!(!is("/rec/20261018T142530Z.mkv"));
+        assert!(!is("/rec/20261018T142530.123Z-.mkv"));
+        assert!(!is("/rec/20261018T142530.123Z-x.mkv"));
+        assert!(!is("/rec/notes.mkv"));
+        assert!(!is("/rec/ääää.mkv"));
+    }
+
+    #[test]
+    fn recorder_cuts_on_time_and_size() {
+        let conf = RecordConf {
+            segment_time: 600,
+            max_size_mb: Some(1),
+            ..RecordConf::default()
+        };
+        let mut recorder = conf.recorder();
+        assert!(!recorder.cut(0, US));
+        assert!(!recorder.cut(599_000_000, US));
+        assert!(recorder.cut(600_000_000, US));
+        // the new file started at 600 s
+        assert!(!recorder.cut(1_199_000_000, US));
+        recorder.written(1_000_000);
+        assert!(recorder.cut(1_199_500_000, US));
+        assert!(!recorder.cut(1_200_000_000, US));
+    }
+
+    #[test]
+    fn prune_keeps_the_open_file_but_counts_it() {
+        let dir = std::env::temp_dir().join(format!("ffmtrans-prune-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let write = |name: &str, size: usize| {
+            let path = dir.join(name);
+            fs::write(&path, vec![0u8; size]).unwrap();
+            path
+        };
+        let oldest = write("20261018T100000.000Z.mkv", 400_000);
+        let older = write("20261018T110000.000Z.mkv", 300_000);
+        let current = write("20261018T120000.000Z.mkv", 500_000);
+        let other = write("notes.txt", 2_000_000);
+        let conf = RecordConf {
+            max_total_mb: Some(1),
+            ..RecordConf::default()
+        };
+        conf.prune(&dir, "mkv", &current).unwrap();
+        // 1.2 MB with the open file, the oldest one goes
+        assert!(!oldest.exists());
+        assert!(older.exists() && current.exists() && other.exists());
+
+        // a file newer than the open one is not protected
+        let newer = write("20261018T130000.000Z.mkv", 1_500_000);
+        conf.prune(&dir, "mkv", &current).unwrap();
+        assert!(current.exists());
+        assert!(!older.exists() && !newer.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,71 @@
+use serde::Deserialize;
+use std::time::{Duration, Instant};
+
+// longest single pause, keeps the session loop responsive
+const MAX_PACE_WAIT: Duration = Duration::from_secs(1);
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum EofAction {
+    // live source dropped, reopen it with backoff
+    #[default]
+    Reconnect,
+    // end the session and report it finished
+    Stop,
+    // rewind and keep going, output timestamps continue
+    Loop,
+}
+
+// holds reading back to the rate timestamps advance at, so that files behave like live sources
+#[derive(Default)]
+pub struct Pacer {
+    // wall clock and session time of the first packet
+    origin: Option<(Instant, i64)>,
+}
+
+impl Pacer {
+    // how long to wait before handling a packet at `session_us`
+    pub fn delay(&mut self, session_us: i64) -> Option<Duration> {
+        let (start, first) = *self.origin.get_or_insert((Instant::now(), session_us));
+        let ahead = session_us - first - start.elapsed().as_micros() as i64;
+        match ahead > 0 {
+            true => Some(Duration::from_micros(ahead as u64).min(MAX_PACE_WAIT)),
+            false => None,
+        }
+    }
+
+    // start over, e.g. after a reconnect left a gap in wall clock time
+    pub fn reset(&mut self) {
+        self.origin = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pacer_waits_for_packets_ahead_of_the_wall_clock() {
+        let mut pacer = Pacer::default();
+        assert_eq!(pacer.delay(5_000_000), None);
+        let delay = pacer.delay(5_500_000).unwrap();
+        assert!(delay > Duration::ZERO && delay <= Duration::from_millis(500));
+        // late packets go out right away
+        assert_eq!(pacer.delay(5_000_000), None);
+    }
+
+    #[test]
+    fn pacer_caps_the_wait() {
+        let mut pacer = Pacer::default();
+        pacer.delay(0);
+        assert_eq!(pacer.delay(60_000_000), Some(MAX_PACE_WAIT));
+    }
+
+    #[test]
+    fn pacer_reset_starts_over() {
+        let mut pacer = Pacer::default();
+        pacer.delay(0);
+        pacer.reset();
+        assert_eq!(pacer.delay(60_000_000), None);
+    }
+}
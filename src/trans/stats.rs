@@ -0,0 +1,228 @@
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use super::ffmpeg::StreamInfo;
+use super::sync::SyncCtx;
+
+// no packet for this long means the input is stalled
+const STALL_MS: u64 = 5000;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    Starting,
+    Running,
+    Stalled,
+    Reconnecting,
+    Stopped,
+    // a file input reached its end
+    Finished,
+    Failed,
+}
+
+impl State {
+    pub fn is_alive(&self) -> bool {
+        !matches!(self, State::Stopped | State::Finished | State::Failed)
+    }
+}
+
+// one destination of a fan-out session
+#[derive(Serialize, Debug, Clone)]
+pub struct OutputStatus {
+    pub output: String,
+    pub state: State,
+}
+
+pub struct Stats {
+    started: Instant,
+    state: Mutex<State>,
+    input: Mutex<Option<StreamInfo>>,
+    outputs: Mutex<Vec<OutputStatus>>,
+    last_error: Mutex<Option<String>>,
+    // ms since `started`
+    last_packet: AtomicU64,
+    pub packets_video: AtomicU64,
+    pub packets_audio: AtomicU64,
+    pub frames_decoded: AtomicU64,
+    pub frames_filtered: AtomicU64,
+    pub frames_encoded: AtomicU64,
+    pub frames_dropped: AtomicU64,
+    pub decode_errors: AtomicU64,
+    pub encode_errors: AtomicU64,
+    pub bytes_written: AtomicU64,
+    pub restarts: AtomicU64,
+    pub input_reconnects: AtomicU64,
+    pub output_reconnects: AtomicU64,
+    // f64 bits
+    audio_time: AtomicU64,
+    video_time: AtomicU64,
+    drift: AtomicU64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Status {
+    pub state: State,
+    pub uptime: f64,
+    pub input: Option<StreamInfo>,
+    pub outputs: Vec<OutputStatus>,
+    pub packets_video: u64,
+    pub packets_audio: u64,
+    pub frames_decoded: u64,
+    pub frames_filtered: u64,
+    pub frames_encoded: u64,
+    pub frames_dropped: u64,
+    pub decode_errors: u64,
+    pub encode_errors: u64,
+    pub bytes_written: u64,
+    pub restarts: u64,
+    pub input_reconnects: u64,
+    pub output_reconnects: u64,
+    // bit/s averaged over uptime
+    pub bitrate: f64,
+    pub audio_time: f64,
+    pub video_time: f64,
+    // audio clock minus video clock averaged over recent packets, in seconds
+    pub drift: f64,
+    pub last_error: Option<String>,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Stats {
+            started: Instant::now(),
+            state: Mutex::new(State::Starting),
+            input: Mutex::new(None),
+            outputs: Mutex::new(Vec::new()),
+            last_error: Mutex::new(None),
+            last_packet: AtomicU64::new(0),
+            packets_video: AtomicU64::new(0),
+            packets_audio: AtomicU64::new(0),
+            frames_decoded: AtomicU64::new(0),
+            frames_filtered: AtomicU64::new(0),
+            frames_encoded: AtomicU64::new(0),
+            frames_dropped: AtomicU64::new(0),
+            decode_errors: AtomicU64::new(0),
+            encode_errors: AtomicU64::new(0),
+            bytes_written: AtomicU64::new(0),
+            restarts: AtomicU64::new(0),
+            input_reconnects: AtomicU64::new(0),
+            output_reconnects: AtomicU64::new(0),
+            audio_time: AtomicU64::new(0),
+            video_time: AtomicU64::new(0),
+            drift: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Stats {
+    pub fn incr(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn running(&self, info: StreamInfo) {
+        *self.input.lock().unwrap() = Some(info);
+        self.touch();
+        *self.state.lock().unwrap() = State::Running;
+    }
+
+    pub fn reconnecting(&self) {
+        *self.state.lock().unwrap() = State::Reconnecting;
+    }
+
+    pub fn resumed(&self) {
+        self.touch();
+        *self.state.lock().unwrap() = State::Running;
+    }
+
+    pub fn stopped(&self) {
+        *self.state.lock().unwrap() = State::Stopped;
+    }
+
+    pub fn finished(&self) {
+        *self.state.lock().unwrap() = State::Finished;
+    }
+
+    pub fn failed(&self, err: String) {
+        *self.last_error.lock().unwrap() = Some(err);
+        *self.state.lock().unwrap() = State::Failed;
+    }
+
+    // state of the output at `index`, outputs are registered in order
+    pub fn output(&self, index: usize, output: &str, state: State) {
+        let mut outputs = self.outputs.lock().unwrap();
+        match outputs.get_mut(index) {
+            Some(status) => status.state = state,
+            None => outputs.push(OutputStatus {
+                output: output.to_string(),
+                state,
+            }),
+        }
+    }
+
+    // called for every packet read from the input
+    pub fn touch(&self) {
+        let now = self.started.elapsed().as_millis() as u64;
+        self.last_packet.store(now, Ordering::Relaxed);
+    }
+
+    pub fn written(&self, bytes: usize) {
+        self.bytes_written
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn sync(&self, sync: &SyncCtx) {
+        let audio_time = sync.audio_time.unwrap_or_default();
+        let video_time = sync.video_time.unwrap_or_default();
+        self.audio_time
+            .store(audio_time.to_bits(), Ordering::Relaxed);
+        self.video_time
+            .store(video_time.to_bits(), Ordering::Relaxed);
+        self.drift.store(sync.drift.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn state(&self) -> State {
+        let state = *self.state.lock().unwrap();
+        // touch() may store a later time between the two loads
+        let idle = (self.started.elapsed().as_millis() as u64)
+            .saturating_sub(self.last_packet.load(Ordering::Relaxed));
+        match state {
+            State::Running if idle > STALL_MS => State::Stalled,
+            _ => state,
+        }
+    }
+
+    pub fn status(&self) -> Status {
+        let uptime = self.started.elapsed().as_secs_f64();
+        let bytes_written = self.bytes_written.load(Ordering::Relaxed);
+        let audio_time = f64::from_bits(self.audio_time.load(Ordering::Relaxed));
+        let video_time = f64::from_bits(self.video_time.load(Ordering::Relaxed));
+        Status {
+            state: self.state(),
+            uptime,
+            input: self.input.lock().unwrap().clone(),
+            outputs: self.outputs.lock().unwrap().clone(),
+            packets_video: self.packets_video.load(Ordering::Relaxed),
+            packets_audio: self.packets_audio.load(Ordering::Relaxed),
+            frames_decoded: self.frames_decoded.load(Ordering::Relaxed),
+            frames_filtered: self.frames_filtered.load(Ordering::Relaxed),
+            frames_encoded: self.frames_encoded.load(Ordering::Relaxed),
+            frames_dropped: self.frames_dropped.load(Ordering::Relaxed),
+            decode_errors: self.decode_errors.load(Ordering::Relaxed),
+            encode_errors: self.encode_errors.load(Ordering::Relaxed),
+            bytes_written,
+            restarts: self.restarts.load(Ordering::Relaxed),
+            input_reconnects: self.input_reconnects.load(Ordering::Relaxed),
+            output_reconnects: self.output_reconnects.load(Ordering::Relaxed),
+            bitrate: match uptime > 0.0 {
+                true => bytes_written as f64 * 8.0 / uptime,
+                false => 0.0,
+            },
+            audio_time,
+            video_time,
+            drift: f64::from_bits(self.drift.load(Ordering::Relaxed)),
+            last_error: self.last_error.lock().unwrap().clone(),
+        }
+    }
+}
@@ -1,5 +1,285 @@
-#[derive(Default, Debug)]
-pub struct TimeGap {
-    pub audio_time: f64,
-    pub video_time: f64,
+use ffmpeg_next::{format::stream::Stream, media::Type, Packet, Rational, Rescale};
+use std::collections::HashMap;
+
+// session clock unit, microseconds
+pub const US: Rational = Rational(1, 1_000_000);
+// a forward jump larger than this is a discontinuity, not a gap in the input
+const MAX_GAP_US: i64 = 10_000_000;
+// backward jumps up to this are left to the output stage to smooth out
+const MAX_BACKWARD_US: i64 = 1_000_000;
+// weight of a new sample in the drift average
+const DRIFT_SMOOTHING: f64 = 0.05;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rebase {
+    // input reopened: continue where the previous input stopped
+    Continue,
+    // output reopened: start the new output at zero
+    Zero,
+}
+
+// one input stream, timestamps in its own time base
+struct InClock {
+    time_base: Rational,
+    // 2^pts_wrap_bits, 0 if the demuxer doesn't wrap
+    wrap: i64,
+    // added to raw timestamps for the wraparounds seen so far
+    unwrap: i64,
+    // last unwrapped timestamp
+    last: Option<i64>,
+    // session time of the last packet and its duration
+    last_session: i64,
+    duration: i64,
+}
+
+impl InClock {
+    fn new(time_base: Rational, wrap_bits: i32) -> Self {
+        InClock {
+            time_base,
+            wrap: match wrap_bits {
+                1..=62 => 1 << wrap_bits,
+                _ => 0,
+            },
+            unwrap: 0,
+            last: None,
+            last_session: 0,
+            duration: 0,
+        }
+    }
+}
+
+// Maps input timestamps onto one monotonic session clock and keeps every output
+// stream's dts increasing. Input side: wraparounds are undone and jumps (camera
+// restarts, reconnects) are closed by shifting the whole session, so audio and
+// video move together. Output side: the session is shifted to start at zero.
+pub struct SyncCtx {
+    inputs: HashMap<usize, InClock>,
+    // last dts written per output stream, in its time base
+    outputs: HashMap<usize, i64>,
+    // added to input timestamps, session clock
+    in_offset: i64,
+    // subtracted from output timestamps, session clock
+    out_offset: i64,
+    // end of the latest packet of any stream, session clock
+    end: i64,
+    in_rebase: bool,
+    out_rebase: bool,
+    // session time of the last video packet written
+    video_clock: Option<i64>,
+    // output clocks in seconds, for status
+    pub audio_time: Option<f64>,
+    pub video_time: Option<f64>,
+    // moving average of audio clock minus video clock, in seconds
+    pub drift: f64,
+}
+
+impl Default for SyncCtx {
+    fn default() -> Self {
+        SyncCtx {
+            inputs: HashMap::new(),
+            outputs: HashMap::new(),
+            in_offset: 0,
+            out_offset: 0,
+            end: 0,
+            in_rebase: false,
+            // the first output packet starts at zero
+            out_rebase: true,
+            video_clock: None,
+            audio_time: None,
+            video_time: None,
+            drift: 0.0,
+        }
+    }
+}
+
+impl SyncCtx {
+    pub fn rebase(&mut self, rebase: Rebase) {
+        match rebase {
+            Rebase::Continue => {
+                // a new input starts a new wraparound and continuity history
+                self.inputs.clear();
+                self.in_rebase = true;
+            }
+            Rebase::Zero => {
+                self.outputs.clear();
+                self.out_rebase = true;
+            }
+        }
+    }
+
+    // normalize an input packet in place, still in the input stream time base.
+    // Returns its session time in microseconds.
+    pub fn input(&mut self, packet: &mut Packet, stream: &Stream) -> Option<i64> {
+        let wrap_bits = unsafe { (*stream.as_ptr()).pts_wrap_bits };
+        self.input_at(packet, stream.index(), stream.time_base(), wrap_bits)
+    }
+
+    fn input_at(
+        &mut self,
+        packet: &mut Packet,
+        index: usize,
+        time_base: Rational,
+        wrap_bits: i32,
+    ) -> Option<i64> {
+        let clock = self
+            .inputs
+            .entry(index)
+            .or_insert_with(|| InClock::new(time_base, wrap_bits));
+        let ts = packet.dts().or(packet.pts())?;
+        // wraparound: a backward jump of more than half the range
+        let mut unwrapped = ts + clock.unwrap;
+        if let Some(last) = clock.last {
+            if clock.wrap > 0 && last - unwrapped > clock.wrap / 2 {
+                clock.unwrap += clock.wrap;
+                unwrapped += clock.wrap;
+            }
+        }
+        let session = unwrapped.rescale(clock.time_base, US) + self.in_offset;
+        let jump = session - clock.last_session;
+        let target = if self.in_rebase {
+            // reconnected, continue right after the previous input
+            self.in_rebase = false;
+            Some(self.end)
+        } else if clock.last.is_some() && !(-MAX_BACKWARD_US..=MAX_GAP_US).contains(&jump) {
+            log::warn!("timestamp discontinuity of {} us", jump);
+            Some(clock.last_session + clock.duration)
+        } else {
+            None
+        };
+        let session = match target {
+            Some(target) => {
+                self.in_offset += target - session;
+                target
+            }
+            None => session,
+        };
+
+        // smooth the step used after the next discontinuity
+        let duration = packet.duration().rescale(clock.time_base, US);
+        if duration > 0 {
+            clock.duration = duration;
+        } else if clock.last.is_some() && (1..MAX_GAP_US).contains(&jump) && target.is_none() {
+            clock.duration = jump;
+        }
+        clock.last = Some(unwrapped);
+        clock.last_session = session;
+        self.end = self.end.max(session + clock.duration);
+
+        let shift = clock.unwrap + self.in_offset.rescale(US, clock.time_base);
+        if shift != 0 {
+            packet.set_pts(packet.pts().map(|pts| pts + shift));
+            packet.set_dts(packet.dts().map(|dts| dts + shift));
+        }
+        Some(session)
+    }
+
+    // shift an output packet onto the output clock and keep its stream's dts increasing
+    pub fn output(&mut self, packet: &mut Packet, time_base: Rational, medium: Type) {
+        let ts = match packet.dts().or(packet.pts()) {
+            Some(ts) => ts,
+            None => return,
+        };
+        if self.out_rebase {
+            self.out_offset = ts.rescale(time_base, US);
+            self.out_rebase = false;
+        }
+        if medium == Type::Video {
+            self.video_clock = Some(packet.pts().unwrap_or(ts).rescale(time_base, US));
+        }
+        let shift = self.out_offset.rescale(US, time_base);
+        let last = self.outputs.get(&packet.stream()).copied().unwrap_or(-1);
+        let dts = packet.dts().map(|dts| (dts - shift).max(last + 1));
+        let pts = packet
+            .pts()
+            .map(|pts| (pts - shift).max(dts.unwrap_or(last + 1)));
+        packet.set_dts(dts);
+        packet.set_pts(pts);
+        if let Some(ts) = dts.or(pts) {
+            self.outputs.insert(packet.stream(), ts);
+        }
+
+        let time = pts.or(dts).unwrap_or(0) as f64 * f64::from(time_base);
+        match medium {
+            Type::Video => self.video_time = Some(time),
+            _ => self.audio_time = Some(time),
+        }
+        if let (Some(audio_time), Some(video_time)) = (self.audio_time, self.video_time) {
+            self.drift += (audio_time - video_time - self.drift) * DRIFT_SMOOTHING;
+        }
+    }
+
+    // session time of the last video packet in microseconds, for a generated silent track
+    pub fn video_clock(&self) -> Option<i64> {
+        self.video_clock
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TB: Rational = Rational(1, 90_000);
+    const WRAP: i64 = 1 << 33;
+
+    fn packet(ts: i64, duration: i64) -> Packet {
+        let mut packet = Packet::empty();
+        packet.set_pts(Some(ts));
+        packet.set_dts(Some(ts));
+        packet.set_duration(duration);
+        packet
+    }
+
+    #[test]
+    fn input_undoes_wraparound() {
+        let mut sync = SyncCtx::default();
+        let before = sync
+            .input_at(&mut packet(WRAP - 3000, 3000), 0, TB, 33)
+            .unwrap();
+        let mut wrapped = packet(0, 3000);
+        let after = sync.input_at(&mut wrapped, 0, TB, 33).unwrap();
+        assert_eq!(wrapped.dts(), Some(WRAP));
+        assert_eq!(after - before, 33_333);
+    }
+
+    #[test]
+    fn input_closes_discontinuities() {
+        let mut sync = SyncCtx::default();
+        assert_eq!(sync.input_at(&mut packet(0, 3000), 0, TB, 33), Some(0));
+        assert_eq!(
+            sync.input_at(&mut packet(3000, 3000), 0, TB, 33),
+            Some(33_333)
+        );
+        // the camera restarted a minute ahead, continue one frame later
+        let mut jumped = packet(3000 + 60 * 90_000, 3000);
+        assert_eq!(sync.input_at(&mut jumped, 0, TB, 33), Some(66_666));
+        assert_eq!(jumped.dts(), Some(6000));
+        // later packets keep the new offset
+        let mut next = packet(6000 + 60 * 90_000, 3000);
+        assert_eq!(sync.input_at(&mut next, 0, TB, 33), Some(100_000));
+    }
+
+    #[test]
+    fn input_continues_after_rebase() {
+        let mut sync = SyncCtx::default();
+        sync.input_at(&mut packet(0, 3000), 0, TB, 33);
+        sync.input_at(&mut packet(3000, 3000), 0, TB, 33);
+        sync.rebase(Rebase::Continue);
+        // a reopened input starting from scratch
+        assert_eq!(sync.input_at(&mut packet(0, 3000), 0, TB, 33), Some(66_666));
+    }
+
+    #[test]
+    fn output_starts_at_zero_and_keeps_dts_increasing() {
+        let mut sync = SyncCtx::default();
+        let mut first = packet(900_000, 3000);
+        sync.output(&mut first, TB, Type::Video);
+        assert_eq!(first.dts(), Some(0));
+        let mut second = packet(903_000, 3000);
+        sync.output(&mut second, TB, Type::Video);
+        assert_eq!(second.dts(), Some(3000));
+        let mut repeated = packet(903_000, 3000);
+        sync.output(&mut repeated, TB, Type::Video);
+        assert_eq!(repeated.dts(), Some(3001));
+        assert_eq!(sync.video_clock(), Some(10_033_333));
+    }
 }